@@ -0,0 +1,157 @@
+// Load test for the redirect endpoint: many keep-alive clients hammering one short link
+//
+// Start the server with the redirect rate limit disabled, create a link, then run:
+// URL_SHORTENER_RATE_LIMIT_REDIRECT_PER_MINUTE=0 cargo run --release
+// cargo run --release --example redirect_load -- http://127.0.0.1:8080/q3-report 64 10
+//
+// Arguments: short URL, number of concurrent clients (default 32), duration in seconds (default 10)
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct ClientResult {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, u64>,
+    errors: u64,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("Usage: {} <short-url> [clients] [seconds]", args[0]);
+        process::exit(1);
+    }
+    let (host, path) = parse_url(&args[1]).unwrap_or_else(|| {
+        eprintln!("Only plain http://host:port/path URLs are supported");
+        process::exit(1);
+    });
+    let clients: usize = args.get(2).and_then(|v| v.parse().ok()).unwrap_or(32);
+    let duration = Duration::from_secs(args.get(3).and_then(|v| v.parse().ok()).unwrap_or(10));
+
+    println!("{} clients requesting http://{}{} for {}s", clients, host, path, duration.as_secs());
+    let started = Instant::now();
+    let handles: Vec<_> = (0..clients)
+        .map(|_| {
+            let host = host.clone();
+            let path = path.clone();
+            thread::spawn(move || run_client(&host, &path, started + duration))
+        })
+        .collect();
+
+    let mut latencies = Vec::new();
+    let mut statuses = BTreeMap::new();
+    let mut errors = 0;
+    for handle in handles {
+        let result = handle.join().expect("Client thread panicked");
+        latencies.extend(result.latencies);
+        for (status, count) in result.statuses {
+            *statuses.entry(status).or_insert(0) += count;
+        }
+        errors += result.errors;
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+
+    latencies.sort_unstable();
+    println!("requests:   {}", latencies.len());
+    println!("throughput: {:.0} req/s", latencies.len() as f64 / elapsed);
+    for (label, pct) in [("p50", 0.50), ("p95", 0.95), ("p99", 0.99)] {
+        if let Some(latency) = percentile(&latencies, pct) {
+            println!("{}:        {:.2} ms", label, latency.as_secs_f64() * 1000.0);
+        }
+    }
+    for (status, count) in statuses {
+        println!("status {}: {}", status, count);
+    }
+    if errors > 0 {
+        println!("connection errors: {}", errors);
+    }
+}
+
+fn run_client(host: &str, path: &str, deadline: Instant) -> ClientResult {
+    let mut result = ClientResult { latencies: Vec::new(), statuses: BTreeMap::new(), errors: 0 };
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: redirect_load\r\n\r\n", path, host);
+
+    while Instant::now() < deadline {
+        let stream = match TcpStream::connect(host) {
+            Ok(stream) => stream,
+            Err(_) => {
+                result.errors += 1;
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+        };
+        let mut writer = stream.try_clone().expect("Failed to clone stream");
+        let mut reader = BufReader::new(stream);
+
+        // Reuse the connection until it fails or the test is over
+        while Instant::now() < deadline {
+            let sent = Instant::now();
+            let status = writer
+                .write_all(request.as_bytes())
+                .ok()
+                .and_then(|_| read_response(&mut reader));
+            match status {
+                Some(status) => {
+                    result.latencies.push(sent.elapsed());
+                    *result.statuses.entry(status).or_insert(0) += 1;
+                }
+                None => {
+                    result.errors += 1;
+                    break;
+                }
+            }
+        }
+    }
+    result
+}
+
+// Reads one response and returns its status code
+fn read_response(reader: &mut BufReader<TcpStream>) -> Option<u16> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let status = line.split_whitespace().nth(1)?.parse().ok()?;
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    Some(status)
+}
+
+fn parse_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+    Some((host, path.to_string()))
+}
+
+fn percentile(sorted: &[Duration], pct: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let index = ((sorted.len() - 1) as f64 * pct).round() as usize;
+    Some(sorted[index])
+}
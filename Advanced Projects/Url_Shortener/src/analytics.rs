@@ -0,0 +1,126 @@
+// Click tracking for short links and the aggregates behind GET /{id}/stats
+
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// How many referrers to list in the stats response
+pub const TOP_REFERRERS: usize = 10;
+
+pub struct Click {
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    // The peer address, or the forwarded one when rate_limit.trust_forwarded_headers is set
+    pub client_ip: Option<String>,
+    // Only used to pick a target, never stored
+    pub accept_language: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LinkStats {
+    pub id: String,
+    pub total_clicks: i64,
+    pub unique_visitors: i64,
+    pub clicks_per_day: Vec<DailyClicks>,
+    pub top_referrers: Vec<ReferrerClicks>,
+    // Visits per target, in target order; the rest of total_clicks went to original_url
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<TargetClicks>,
+}
+
+#[derive(Serialize)]
+pub struct DailyClicks {
+    pub date: String,
+    pub clicks: i64,
+}
+
+#[derive(Serialize)]
+pub struct ReferrerClicks {
+    pub referrer: String,
+    pub clicks: i64,
+}
+
+// Stats need no API key, so targets are only identified by their index in the link's targets. Their URLs
+// are left to the owner, who sees them in GET /links; a password would protect nothing otherwise.
+#[derive(Serialize)]
+pub struct TargetClicks {
+    pub position: usize,
+    pub clicks: i64,
+}
+
+pub fn record_click(conn: &Connection, url_id: &str, click: &Click, ip_salt: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO clicks (url_id, clicked_at, referrer, user_agent, ip_hash)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            url_id,
+            Utc::now().timestamp(),
+            click.referrer,
+            click.user_agent,
+            click.client_ip.as_deref().map(|ip| hash_ip(ip, ip_salt)),
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn link_stats(conn: &Connection, url_id: &str) -> rusqlite::Result<LinkStats> {
+    let (total_clicks, unique_visitors) = conn.query_row(
+        "SELECT COUNT(*), COUNT(DISTINCT ip_hash) FROM clicks WHERE url_id = ?1",
+        params![url_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT date(clicked_at, 'unixepoch') AS day, COUNT(*)
+         FROM clicks WHERE url_id = ?1
+         GROUP BY day ORDER BY day",
+    )?;
+    let clicks_per_day = stmt
+        .query_map(params![url_id], |row| {
+            Ok(DailyClicks { date: row.get(0)?, clicks: row.get(1)? })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut stmt = conn.prepare(
+        "SELECT referrer, COUNT(*) AS hits
+         FROM clicks WHERE url_id = ?1 AND referrer IS NOT NULL
+         GROUP BY referrer ORDER BY hits DESC, referrer LIMIT ?2",
+    )?;
+    let top_referrers = stmt
+        .query_map(params![url_id, TOP_REFERRERS as i64], |row| {
+            Ok(ReferrerClicks { referrer: row.get(0)?, clicks: row.get(1)? })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut stmt =
+        conn.prepare("SELECT position, click_count FROM link_targets WHERE url_id = ?1 ORDER BY position")?;
+    let targets = stmt
+        .query_map(params![url_id], |row| Ok(TargetClicks { position: row.get(0)?, clicks: row.get(1)? }))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(LinkStats {
+        id: url_id.to_string(),
+        total_clicks,
+        unique_visitors,
+        clicks_per_day,
+        top_referrers,
+        targets,
+    })
+}
+
+// The per-instance salt mixed into client IPs, generated when the database is created. Being secret, it keeps
+// the stored hashes from being matched against a list of hashed IPs.
+pub fn load_ip_salt(conn: &Connection) -> rusqlite::Result<String> {
+    let salt = conn
+        .query_row("SELECT value FROM instance_settings WHERE name = 'ip_hash_salt'", [], |row| row.get(0))
+        .optional()?;
+    salt.ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+pub fn hash_ip(ip: &str, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(ip.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
@@ -0,0 +1,105 @@
+// API keys: generated by the `keys` admin subcommand, stored only as SHA-256 hashes and sent by
+// clients as `Authorization: Bearer <key>`
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use chrono::Utc;
+use nanoid::nanoid;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::errors::AppError;
+use crate::store::LinkStore;
+
+const KEY_PREFIX: &str = "us_";
+const KEY_LENGTH: usize = 32;
+
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    pub id: i64,
+    pub name: String,
+    pub created_at: i64,
+    pub revoked: bool,
+}
+
+pub fn missing_key() -> AppError {
+    AppError::Unauthorized("Missing API key, send it as 'Authorization: Bearer <key>'".to_string())
+}
+
+// Returns the owner ID of the key sent with the request
+pub fn require_owner(store: &dyn LinkStore, key: Option<&str>) -> Result<i64, AppError> {
+    optional_owner(store, key)?.ok_or_else(missing_key)
+}
+
+// Anonymous requests are allowed, but a key that is sent must be valid
+pub fn optional_owner(store: &dyn LinkStore, key: Option<&str>) -> Result<Option<i64>, AppError> {
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    match store.owner_for_key(key)? {
+        Some(owner) => Ok(Some(owner)),
+        None => Err(AppError::Unauthorized("Invalid or revoked API key".to_string())),
+    }
+}
+
+pub fn lookup_owner(conn: &Connection, key: &str) -> rusqlite::Result<Option<i64>> {
+    conn.query_row(
+        "SELECT id FROM api_keys WHERE key_hash = ?1 AND revoked_at IS NULL",
+        params![hash_key(key)],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+// Returns the new key's ID and the plaintext key, which is not stored anywhere
+pub fn create_key(conn: &Connection, name: &str) -> rusqlite::Result<(i64, String)> {
+    let key = format!("{}{}", KEY_PREFIX, nanoid!(KEY_LENGTH));
+    conn.execute(
+        "INSERT INTO api_keys (name, key_hash, created_at) VALUES (?1, ?2, ?3)",
+        params![name, hash_key(&key), Utc::now().timestamp()],
+    )?;
+    Ok((conn.last_insert_rowid(), key))
+}
+
+pub fn list_keys(conn: &Connection) -> rusqlite::Result<Vec<ApiKeyInfo>> {
+    let mut stmt = conn.prepare("SELECT id, name, created_at, revoked_at IS NOT NULL FROM api_keys ORDER BY id")?;
+    let keys = stmt
+        .query_map([], |row| {
+            Ok(ApiKeyInfo { id: row.get(0)?, name: row.get(1)?, created_at: row.get(2)?, revoked: row.get(3)? })
+        })?
+        .collect();
+    keys
+}
+
+// Returns false when no active key has that ID
+pub fn revoke_key(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    let updated = conn.execute(
+        "UPDATE api_keys SET revoked_at = ?1 WHERE id = ?2 AND revoked_at IS NULL",
+        params![Utc::now().timestamp(), id],
+    )?;
+    Ok(updated > 0)
+}
+
+pub fn is_active_key(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM api_keys WHERE id = ?1 AND revoked_at IS NULL",
+        params![id],
+        |row| row.get(0),
+    )
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        Some(token.trim().to_string())
+    } else {
+        None
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
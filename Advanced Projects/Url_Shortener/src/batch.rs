@@ -0,0 +1,161 @@
+// Bulk link creation from a JSON array or a CSV upload
+//
+// POST /shorten/batch with `Content-Type: application/json` takes an array of /shorten payloads;
+// `Content-Type: text/csv` takes a header row naming the columns, of which only original_url is required:
+//
+// original_url,alias,expires_at,max_clicks,tags
+// https://example.com/report,q3-report,,,"finance,q3"
+// https://example.com/launch,,2030-01-01T00:00:00Z,100,
+//
+// Every row takes a token of the create rate limit, and a batch larger than what is left is refused with 429.
+// All rows are stored together, in one transaction on SQLite. By default a single bad row rolls back
+// the whole batch; with `?partial=true` the valid rows are kept and only the bad ones are reported.
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::AppError;
+use crate::{auth, prepare_link, rate_limit, request_owner, store, validation, AppState, UrlPayload};
+
+const MAX_BATCH_ROWS: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct BatchQuery {
+    #[serde(default)]
+    partial: bool,
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    created: usize,
+    failed: usize,
+    results: Vec<RowResult>,
+}
+
+#[derive(Serialize)]
+struct RowResult {
+    // 1-based position in the input, not counting the CSV header
+    row: usize,
+    // "created", "failed", or "skipped" for valid rows of a batch that was rolled back
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shortened_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+pub async fn shorten_batch(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<BatchQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, AppError> {
+    let rows = match req.content_type() {
+        "application/json" => parse_json(&body)?,
+        "text/csv" => parse_csv(&body)?,
+        other => {
+            return Err(AppError::Validation(format!(
+                "Unsupported content type '{}', send application/json or text/csv",
+                other
+            )))
+        }
+    };
+    if rows.is_empty() {
+        return Err(AppError::Validation("The batch contains no rows".to_string()));
+    }
+    if rows.len() > MAX_BATCH_ROWS {
+        return Err(AppError::Validation(format!("A batch may contain at most {} rows", MAX_BATCH_ROWS)));
+    }
+    rate_limit::charge(&req, rows.len() as u32)?;
+
+    let policy = data.policy.current();
+    let partial = query.partial;
+    let api_key = auth::bearer_token(&req);
+    let config = data.config.clone();
+
+    let batch = store::run(&data.store, move |store| {
+        let owner_id = request_owner(store, &config, api_key.as_deref())?;
+        let links = rows
+            .into_iter()
+            .map(|row| {
+                let mut link = prepare_link(&row?, &policy)?;
+                link.owner_id = owner_id;
+                Ok(link)
+            })
+            .collect();
+        store.create_many(links, partial)
+    })
+    .await?;
+    let committed = batch.committed;
+    // Reused links were counted when they were first created
+    let inserted = batch.outcomes.iter().filter(|outcome| matches!(outcome, Ok(created) if created.inserted)).count();
+
+    let results: Vec<RowResult> = batch
+        .outcomes
+        .into_iter()
+        .enumerate()
+        .map(|(i, outcome)| match outcome {
+            Ok(created) if committed => RowResult {
+                row: i + 1,
+                status: "created",
+                shortened_url: Some(data.config.short_url(&created.id)),
+                error: None,
+                message: None,
+            },
+            Ok(_) => RowResult { row: i + 1, status: "skipped", shortened_url: None, error: None, message: None },
+            Err(e) => RowResult {
+                row: i + 1,
+                status: "failed",
+                shortened_url: None,
+                error: Some(e.code()),
+                message: Some(e.message().to_string()),
+            },
+        })
+        .collect();
+    let created = results.iter().filter(|r| r.status == "created").count();
+    if committed {
+        data.metrics.links_created.inc_by(inserted as u64);
+    }
+    let failed = results.iter().filter(|r| r.status == "failed").count();
+
+    let status = if committed { StatusCode::OK } else { StatusCode::UNPROCESSABLE_ENTITY };
+    Ok(HttpResponse::build(status).json(BatchResponse { created, failed, results }))
+}
+
+fn parse_json(body: &[u8]) -> Result<Vec<Result<UrlPayload, AppError>>, AppError> {
+    // Parse each element separately so one malformed entry doesn't hide the rest
+    let values: Vec<serde_json::Value> = serde_json::from_slice(body)
+        .map_err(|e| AppError::Validation(format!("Expected a JSON array of links: {}", e)))?;
+    Ok(values
+        .into_iter()
+        .map(|value| serde_json::from_value(value).map_err(|e| AppError::Validation(e.to_string())))
+        .collect())
+}
+
+fn parse_csv(body: &[u8]) -> Result<Vec<Result<UrlPayload, AppError>>, AppError> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(body);
+    let headers = reader
+        .headers()
+        .map_err(|e| AppError::Validation(format!("Invalid CSV header: {}", e)))?
+        .clone();
+    if !headers.iter().any(|h| h == "original_url") {
+        return Err(AppError::Validation("The CSV header must include an original_url column".to_string()));
+    }
+    // Cells are typed by guessing, which would turn a tag like "007" or "1e5" into a number
+    let tags_column = headers.iter().position(|h| h == "tags");
+    Ok(reader
+        .records()
+        .map(|row| {
+            let row = row.map_err(|e| AppError::Validation(format!("Invalid CSV row: {}", e)))?;
+            let mut payload: UrlPayload =
+                row.deserialize(Some(&headers)).map_err(|e| AppError::Validation(format!("Invalid CSV row: {}", e)))?;
+            if let Some(cell) = tags_column.and_then(|i| row.get(i)) {
+                payload.tags = validation::split_tag_list(cell);
+            }
+            Ok(payload)
+        })
+        .collect())
+}
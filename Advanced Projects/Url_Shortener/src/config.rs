@@ -0,0 +1,289 @@
+// Runtime settings, layered as: built-in defaults < TOML file < environment variables < command-line flags
+
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use url::Url;
+
+use crate::ids::IdStrategy;
+use crate::rate_limit::RateLimitConfig;
+use crate::store::cache::CacheConfig;
+use crate::store::Backend;
+
+// Read when no --config flag or URL_SHORTENER_CONFIG variable is given, but only if it exists
+const DEFAULT_CONFIG_FILE: &str = "url_shortener.toml";
+// Same alphabet nanoid uses by default, kept to characters that are safe in a URL path
+const DEFAULT_ID_ALPHABET: &str = "_-0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const MIN_ID_LENGTH: usize = 4;
+const MAX_ID_LENGTH: usize = 64;
+const MIN_ALPHABET_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: String,
+    pub base_url: String,
+    // Where links are kept: "sqlite" (database_path) or "memory", which loses everything on restart
+    pub storage: Backend,
+    pub database_path: String,
+    // Number of SQLite connections shared by the request handlers
+    pub db_pool_size: u32,
+    // How short IDs are generated: "random", "counter" or "hash", see ids.rs
+    pub id_strategy: IdStrategy,
+    // Used by the random and hash strategies
+    pub id_length: usize,
+    // Used by the random strategy; counter and hash IDs are base62
+    pub id_alphabet: String,
+    // When false, /shorten also accepts requests without an API key and creates unowned links
+    pub require_api_key: bool,
+    // TOML file of blocked and allowed destinations; without one every destination is allowed
+    pub policy_file: Option<String>,
+    pub rate_limit: RateLimitConfig,
+    pub cache: CacheConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: "127.0.0.1:8080".to_string(),
+            base_url: "http://127.0.0.1:8080".to_string(),
+            storage: Backend::Sqlite,
+            database_path: "url_shortener.db".to_string(),
+            db_pool_size: 8,
+            id_strategy: IdStrategy::Random,
+            id_length: 8,
+            id_alphabet: DEFAULT_ID_ALPHABET.to_string(),
+            require_api_key: false,
+            policy_file: None,
+            rate_limit: RateLimitConfig::default(),
+            cache: CacheConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(matches: &ArgMatches) -> Result<Config, Vec<String>> {
+        let mut config = match config_file(matches) {
+            Some(path) => Config::from_file(&path).map_err(|e| vec![e])?,
+            None => Config::default(),
+        };
+        let mut errors = Vec::new();
+        config.apply_env(&mut errors);
+        config.apply_args(matches);
+        config.validate(&mut errors);
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn short_url(&self, id: &str) -> String {
+        format!("{}/{}", self.base_url, id)
+    }
+
+    pub fn id_alphabet_chars(&self) -> Vec<char> {
+        self.id_alphabet.chars().collect()
+    }
+
+    fn from_file(path: &str) -> Result<Config, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
+        toml::from_str(&contents).map_err(|e| format!("Invalid config file {}: {}", path, e))
+    }
+
+    fn apply_env(&mut self, errors: &mut Vec<String>) {
+        if let Ok(value) = env::var("URL_SHORTENER_BIND") {
+            self.bind_address = value;
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_BASE_URL") {
+            self.base_url = value;
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_STORAGE") {
+            match Backend::from_name(&value) {
+                Some(backend) => self.storage = backend,
+                None => errors.push(format!("URL_SHORTENER_STORAGE must be sqlite or memory, got '{}'", value)),
+            }
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_DATABASE") {
+            self.database_path = value;
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_DB_POOL_SIZE") {
+            match value.parse() {
+                Ok(size) => self.db_pool_size = size,
+                Err(_) => errors.push(format!("URL_SHORTENER_DB_POOL_SIZE must be a number, got '{}'", value)),
+            }
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_ID_STRATEGY") {
+            match IdStrategy::from_name(&value) {
+                Some(strategy) => self.id_strategy = strategy,
+                None => errors.push(format!(
+                    "URL_SHORTENER_ID_STRATEGY must be random, counter or hash, got '{}'",
+                    value
+                )),
+            }
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_ID_LENGTH") {
+            match value.parse() {
+                Ok(length) => self.id_length = length,
+                Err(_) => errors.push(format!("URL_SHORTENER_ID_LENGTH must be a number, got '{}'", value)),
+            }
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_ID_ALPHABET") {
+            self.id_alphabet = value;
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_REQUIRE_API_KEY") {
+            match value.parse() {
+                Ok(required) => self.require_api_key = required,
+                Err(_) => errors.push(format!("URL_SHORTENER_REQUIRE_API_KEY must be true or false, got '{}'", value)),
+            }
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_POLICY_FILE") {
+            self.policy_file = Some(value);
+        }
+
+        let limits = &mut self.rate_limit;
+        for (name, field) in [
+            ("URL_SHORTENER_RATE_LIMIT_CREATE_PER_MINUTE", &mut limits.create_per_minute),
+            ("URL_SHORTENER_RATE_LIMIT_CREATE_BURST", &mut limits.create_burst),
+            ("URL_SHORTENER_RATE_LIMIT_REDIRECT_PER_MINUTE", &mut limits.redirect_per_minute),
+            ("URL_SHORTENER_RATE_LIMIT_REDIRECT_BURST", &mut limits.redirect_burst),
+        ] {
+            if let Ok(value) = env::var(name) {
+                match value.parse() {
+                    Ok(n) => *field = n,
+                    Err(_) => errors.push(format!("{} must be a number, got '{}'", name, value)),
+                }
+            }
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_RATE_LIMIT_TRUST_FORWARDED_HEADERS") {
+            match value.parse() {
+                Ok(trust) => limits.trust_forwarded_headers = trust,
+                Err(_) => errors.push(format!(
+                    "URL_SHORTENER_RATE_LIMIT_TRUST_FORWARDED_HEADERS must be true or false, got '{}'",
+                    value
+                )),
+            }
+        }
+
+        if let Ok(value) = env::var("URL_SHORTENER_CACHE_CAPACITY") {
+            match value.parse() {
+                Ok(capacity) => self.cache.capacity = capacity,
+                Err(_) => errors.push(format!("URL_SHORTENER_CACHE_CAPACITY must be a number, got '{}'", value)),
+            }
+        }
+        if let Ok(value) = env::var("URL_SHORTENER_CACHE_TTL_SECONDS") {
+            match value.parse() {
+                Ok(ttl) => self.cache.ttl_seconds = ttl,
+                Err(_) => errors.push(format!("URL_SHORTENER_CACHE_TTL_SECONDS must be a number, got '{}'", value)),
+            }
+        }
+    }
+
+    fn apply_args(&mut self, matches: &ArgMatches) {
+        if let Some(value) = matches.get_one::<String>("bind") {
+            self.bind_address = value.clone();
+        }
+        if let Some(value) = matches.get_one::<String>("base-url") {
+            self.base_url = value.clone();
+        }
+        if let Some(value) = matches.get_one::<String>("storage").and_then(|v| Backend::from_name(v)) {
+            self.storage = value;
+        }
+        if let Some(value) = matches.get_one::<String>("database") {
+            self.database_path = value.clone();
+        }
+        if let Some(value) = matches.get_one::<String>("id-strategy").and_then(|v| IdStrategy::from_name(v)) {
+            self.id_strategy = value;
+        }
+        if let Some(value) = matches.get_one::<usize>("id-length") {
+            self.id_length = *value;
+        }
+        if let Some(value) = matches.get_one::<String>("id-alphabet") {
+            self.id_alphabet = value.clone();
+        }
+        if matches.get_flag("require-api-key") {
+            self.require_api_key = true;
+        }
+        if let Some(value) = matches.get_one::<String>("policy-file") {
+            self.policy_file = Some(value.clone());
+        }
+    }
+
+    fn validate(&mut self, errors: &mut Vec<String>) {
+        if self.bind_address.to_socket_addrs().is_err() {
+            errors.push(format!("bind_address '{}' is not a valid host:port address", self.bind_address));
+        }
+
+        match Url::parse(&self.base_url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                if url.query().is_some() || url.fragment().is_some() {
+                    errors.push(format!("base_url '{}' must not contain a query or fragment", self.base_url));
+                }
+            }
+            _ => errors.push(format!("base_url '{}' must be an absolute http or https URL", self.base_url)),
+        }
+        // Short links are built as "{base_url}/{id}"
+        while self.base_url.ends_with('/') {
+            self.base_url.pop();
+        }
+
+        if self.database_path.trim().is_empty() {
+            errors.push("database_path must not be empty".to_string());
+        }
+
+        // API keys are created by the keys subcommand in the database file, which the memory store never reads
+        if self.storage == Backend::Memory && self.require_api_key {
+            errors.push("require_api_key needs the sqlite storage backend, API keys live in the database".to_string());
+        }
+
+        if self.db_pool_size == 0 {
+            errors.push("db_pool_size must be at least 1".to_string());
+        }
+
+        if !(MIN_ID_LENGTH..=MAX_ID_LENGTH).contains(&self.id_length) {
+            errors.push(format!(
+                "id_length must be between {} and {}, got {}",
+                MIN_ID_LENGTH, MAX_ID_LENGTH, self.id_length
+            ));
+        }
+
+        let mut chars = self.id_alphabet_chars();
+        if let Some(c) = chars.iter().find(|c| !(c.is_ascii_alphanumeric() || **c == '-' || **c == '_')) {
+            errors.push(format!("id_alphabet contains '{}', only letters, digits, '-' and '_' are allowed", c));
+        }
+        chars.sort_unstable();
+        chars.dedup();
+        if chars.len() != self.id_alphabet.chars().count() {
+            errors.push("id_alphabet must not contain duplicate characters".to_string());
+        }
+        if chars.len() < MIN_ALPHABET_SIZE {
+            errors.push(format!("id_alphabet must contain at least {} characters", MIN_ALPHABET_SIZE));
+        }
+
+        let limits = &self.rate_limit;
+        if limits.create_per_minute > 0 && limits.create_burst == 0 {
+            errors.push("rate_limit.create_burst must be at least 1 when create_per_minute is set".to_string());
+        }
+        if limits.redirect_per_minute > 0 && limits.redirect_burst == 0 {
+            errors.push("rate_limit.redirect_burst must be at least 1 when redirect_per_minute is set".to_string());
+        }
+
+        if self.cache.capacity > 0 && self.cache.ttl_seconds == 0 {
+            errors.push("cache.ttl_seconds must be at least 1 when capacity is set".to_string());
+        }
+    }
+}
+
+fn config_file(matches: &ArgMatches) -> Option<String> {
+    if let Some(path) = matches.get_one::<String>("config") {
+        return Some(path.clone());
+    }
+    if let Ok(path) = env::var("URL_SHORTENER_CONFIG") {
+        return Some(path);
+    }
+    Path::new(DEFAULT_CONFIG_FILE).exists().then(|| DEFAULT_CONFIG_FILE.to_string())
+}
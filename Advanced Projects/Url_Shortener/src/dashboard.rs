@@ -0,0 +1,17 @@
+// The browser dashboard at GET /dashboard
+//
+// A single static page that shortens, lists, edits and deletes links through the same JSON endpoints as
+// curl does, using an API key the user pastes in.
+
+use actix_web::http::header::{self, ContentType};
+use actix_web::HttpResponse;
+
+const PAGE: &str = include_str!("dashboard.html");
+
+pub async fn dashboard() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        // Revalidate so a new build's page is picked up right away
+        .append_header((header::CACHE_CONTROL, "no-cache"))
+        .body(PAGE)
+}
@@ -0,0 +1,52 @@
+// Pooled SQLite connections
+
+use r2d2::ManageConnection;
+use rusqlite::{Connection, OpenFlags};
+use std::time::Duration;
+
+// How long a connection waits on a lock held by another writer before giving up with SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type Pool = r2d2::Pool<SqliteConnectionManager>;
+
+pub struct SqliteConnectionManager {
+    path: String,
+}
+
+impl ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        open(&self.path)
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        conn.execute_batch("SELECT 1")
+    }
+
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
+    }
+}
+
+// Opens a connection with the settings every connection to the database should share
+pub fn open(path: &str) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // WAL lets readers proceed while a write is in progress; NORMAL sync is safe in WAL mode
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    Ok(conn)
+}
+
+// For looking without touching: no file is created and no pragma is changed
+pub fn open_read_only(path: &str) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+}
+
+pub fn pool(path: &str, size: u32) -> Result<Pool, r2d2::Error> {
+    r2d2::Pool::builder()
+        .max_size(size)
+        .build(SqliteConnectionManager { path: path.to_string() })
+}
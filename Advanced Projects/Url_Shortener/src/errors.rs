@@ -0,0 +1,180 @@
+// Application error type shared by all handlers
+//
+// Every error leaves the service as `{"error": code, "message": ...}` with a matching status code.
+// Database and internal failures are logged in full but only described generically to the client.
+
+use actix_web::error::BlockingError;
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use rusqlite::ErrorCode;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum AppError {
+    Validation(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    Gone(String),
+    RateLimited(String),
+    DatabaseBusy(String),
+    Database(String),
+    Internal(String),
+}
+
+#[derive(Serialize)]
+pub struct ErrorBody<'a> {
+    pub error: &'a str,
+    pub message: &'a str,
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "invalid_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Gone(_) => "gone",
+            AppError::RateLimited(_) => "rate_limited",
+            AppError::DatabaseBusy(_) => "database_busy",
+            AppError::Database(_) => "database_error",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    // The message shown to clients; server-side failures keep their details out of responses
+    pub fn message(&self) -> &str {
+        match self {
+            AppError::DatabaseBusy(_) => "The database is busy, please retry shortly",
+            AppError::Database(_) => "A database error occurred",
+            AppError::Internal(_) => "An internal error occurred",
+            AppError::Validation(m)
+            | AppError::Unauthorized(m)
+            | AppError::Forbidden(m)
+            | AppError::NotFound(m)
+            | AppError::Conflict(m)
+            | AppError::Gone(m)
+            | AppError::RateLimited(m) => m,
+        }
+    }
+
+    // Same as `error_response`, but renders an HTML page for clients that prefer HTML over JSON,
+    // such as browsers following a short link
+    pub fn negotiated_response(&self, req: &HttpRequest) -> HttpResponse {
+        if !prefers_html(req) {
+            return self.error_response();
+        }
+        self.log();
+        let status = self.status_code();
+        let body = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{code} {reason}</title></head>\n\
+             <body>\n<h1>{code} {reason}</h1>\n<p>{message}</p>\n</body>\n</html>\n",
+            code = status.as_u16(),
+            reason = status.canonical_reason().unwrap_or(""),
+            message = escape_html(self.message()),
+        );
+        HttpResponse::build(status).content_type(ContentType::html()).body(body)
+    }
+
+    fn log(&self) {
+        match self {
+            AppError::DatabaseBusy(detail) | AppError::Database(detail) | AppError::Internal(detail) => {
+                eprintln!("{}: {}", self.code(), detail);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Gone(_) => StatusCode::GONE,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::DatabaseBusy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.log();
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::Unauthorized(_) = self {
+            response.append_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(ErrorBody { error: self.code(), message: self.message() })
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(err: rusqlite::Error) -> Self {
+        match &err {
+            rusqlite::Error::SqliteFailure(e, _)
+                if e.code == ErrorCode::DatabaseBusy || e.code == ErrorCode::DatabaseLocked =>
+            {
+                AppError::DatabaseBusy(err.to_string())
+            }
+            _ => AppError::Database(err.to_string()),
+        }
+    }
+}
+
+impl From<r2d2::Error> for AppError {
+    fn from(err: r2d2::Error) -> Self {
+        AppError::DatabaseBusy(format!("Failed to get a database connection: {}", err))
+    }
+}
+
+impl From<BlockingError> for AppError {
+    fn from(err: BlockingError) -> Self {
+        AppError::Internal(format!("Blocking task failed: {}", err))
+    }
+}
+
+fn prefers_html(req: &HttpRequest) -> bool {
+    let accept = match req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return false,
+    };
+    // Pick whichever of HTML and JSON has the higher quality; ties go to the one listed first
+    let mut best: Option<(f32, bool)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let html = match parts.next() {
+            Some("text/html") => true,
+            Some("application/json") => false,
+            _ => continue,
+        };
+        let quality = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(q, _)| quality > q) {
+            best = Some((quality, html));
+        }
+    }
+    matches!(best, Some((_, true)))
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,242 @@
+// How generated short IDs are made, chosen per deployment with the `id_strategy` setting
+//
+// random:  id_length characters drawn from id_alphabet, the default
+// counter: the next number of a sequence kept by the store, in base62, so the first IDs are "1", "2", ...
+// hash:    id_length base62 characters derived from the SHA-256 of the destination, so a URL gets the same ID
+//          on every instance
+//
+// A candidate that is already taken or names a route is a collision. Random draws again, counter moves on to
+// the next number and hash mixes the attempt number into the digest. The same URL stored again, with limits
+// that keep it from reusing the first link, collides with every earlier digest, so after a few of those hash
+// draws random base62 IDs instead.
+
+use nanoid::nanoid;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::validation;
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// How many fresh IDs to try before giving up on a generated short link
+const MAX_ID_ATTEMPTS: usize = 5;
+// Taken sequence numbers are cheap to skip, and imported counter IDs can occupy long runs of them
+const MAX_SEQUENCE_SKIPS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdStrategy {
+    #[default]
+    Random,
+    Counter,
+    Hash,
+}
+
+impl IdStrategy {
+    pub fn from_name(name: &str) -> Option<IdStrategy> {
+        match name {
+            "random" => Some(IdStrategy::Random),
+            "counter" => Some(IdStrategy::Counter),
+            "hash" => Some(IdStrategy::Hash),
+            _ => None,
+        }
+    }
+}
+
+pub struct IdGenerator {
+    strategy: IdStrategy,
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+impl IdGenerator {
+    pub fn new(config: &Config) -> IdGenerator {
+        IdGenerator { strategy: config.id_strategy, alphabet: config.id_alphabet_chars(), length: config.id_length }
+    }
+
+    // Offers candidates to `claim` until it takes one, which it does by returning true. `next_in_sequence`
+    // hands out the store's next counter value and is only called by the counter strategy.
+    pub fn generate(
+        &self,
+        original_url: &str,
+        mut next_in_sequence: impl FnMut() -> Result<u64, AppError>,
+        mut claim: impl FnMut(&str) -> Result<bool, AppError>,
+    ) -> Result<String, AppError> {
+        let attempts = match self.strategy {
+            IdStrategy::Counter => MAX_SEQUENCE_SKIPS,
+            IdStrategy::Random => MAX_ID_ATTEMPTS,
+            IdStrategy::Hash => MAX_ID_ATTEMPTS * 2,
+        };
+        let length = self.length;
+        for attempt in 0..attempts {
+            let id = match self.strategy {
+                IdStrategy::Random => nanoid!(length, &self.alphabet),
+                IdStrategy::Counter => base62(next_in_sequence()?),
+                IdStrategy::Hash if attempt < MAX_ID_ATTEMPTS => self.hashed(original_url, attempt as u64),
+                IdStrategy::Hash => {
+                    let base62_chars: Vec<char> = BASE62.iter().map(|&b| b as char).collect();
+                    nanoid!(length, &base62_chars)
+                }
+            };
+            // A generated ID such as "links" would be shadowed by the route of the same name
+            if validation::is_reserved(&id) {
+                continue;
+            }
+            if claim(&id)? {
+                return Ok(id);
+            }
+        }
+        Err(AppError::Internal(format!("No free short ID after {} attempts", attempts)))
+    }
+
+    // Attempt 0 depends on the URL alone. Digest bytes of 248 and up are skipped so that every character is
+    // equally likely, and further digests are chained on until the ID is long enough.
+    fn hashed(&self, original_url: &str, attempt: u64) -> String {
+        let mut id = String::with_capacity(self.length);
+        let mut block: u32 = 0;
+        loop {
+            let mut hasher = Sha256::new();
+            hasher.update(original_url.as_bytes());
+            if attempt > 0 {
+                hasher.update(attempt.to_be_bytes());
+            }
+            hasher.update(block.to_be_bytes());
+            for byte in hasher.finalize() {
+                if usize::from(byte) < BASE62.len() * 4 {
+                    id.push(BASE62[usize::from(byte) % BASE62.len()] as char);
+                    if id.len() == self.length {
+                        return id;
+                    }
+                }
+            }
+            block += 1;
+        }
+    }
+}
+
+fn base62(mut n: u64) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(BASE62[(n % 62) as usize]);
+        n /= 62;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().map(|&d| d as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(strategy: IdStrategy) -> IdGenerator {
+        IdGenerator { strategy, alphabet: Config::default().id_alphabet_chars(), length: 8 }
+    }
+
+    fn no_sequence() -> Result<u64, AppError> {
+        panic!("only the counter strategy uses the sequence")
+    }
+
+    #[test]
+    fn test_base62() {
+        assert_eq!(base62(0), "0");
+        assert_eq!(base62(61), "z");
+        assert_eq!(base62(62), "10");
+    }
+
+    #[test]
+    fn test_hash_is_deterministic() {
+        let url = "https://example.com/page";
+        let first = generator(IdStrategy::Hash).generate(url, no_sequence, |_| Ok(true)).unwrap();
+        let second = generator(IdStrategy::Hash).generate(url, no_sequence, |_| Ok(true)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 8);
+        assert!(first.bytes().all(|b| BASE62.contains(&b)));
+
+        let other = generator(IdStrategy::Hash).generate("https://example.com/other", no_sequence, |_| Ok(true));
+        assert_ne!(first, other.unwrap());
+    }
+
+    #[test]
+    fn test_hash_collision_tries_another_id() {
+        let url = "https://example.com/page";
+        let taken = generator(IdStrategy::Hash).generate(url, no_sequence, |_| Ok(true)).unwrap();
+
+        let mut offered = Vec::new();
+        let id = generator(IdStrategy::Hash)
+            .generate(url, no_sequence, |id| {
+                offered.push(id.to_string());
+                Ok(id != taken)
+            })
+            .unwrap();
+        assert_ne!(id, taken);
+        assert_eq!(offered, vec![taken, id]);
+    }
+
+    #[test]
+    fn test_hash_falls_back_to_random_ids() {
+        let url = "https://example.com/download";
+        let hash = generator(IdStrategy::Hash);
+        let digests: Vec<String> = (0..MAX_ID_ATTEMPTS as u64).map(|i| hash.hashed(url, i)).collect();
+
+        // Every digest is taken by earlier links to the same URL
+        let id = hash.generate(url, no_sequence, |id| Ok(!digests.iter().any(|d| d == id))).unwrap();
+        assert!(!digests.contains(&id));
+        assert_eq!(id.len(), 8);
+        assert!(id.bytes().all(|b| BASE62.contains(&b)));
+    }
+
+    #[test]
+    fn test_counter_skips_taken_numbers() {
+        let mut sequence = 0;
+        let id = generator(IdStrategy::Counter)
+            .generate(
+                "https://example.com/",
+                || {
+                    sequence += 1;
+                    Ok(sequence)
+                },
+                |id| Ok(id != "1"),
+            )
+            .unwrap();
+        assert_eq!(id, "2");
+    }
+
+    #[test]
+    fn test_reserved_id_is_skipped() {
+        // The sequence number whose base62 form is "links"
+        let links = "links".bytes().fold(0, |n, b| n * 62 + BASE62.iter().position(|&d| d == b).unwrap() as u64);
+        assert_eq!(base62(links), "links");
+
+        let mut sequence = links - 1;
+        let mut offered = Vec::new();
+        let id = generator(IdStrategy::Counter)
+            .generate(
+                "https://example.com/",
+                || {
+                    sequence += 1;
+                    Ok(sequence)
+                },
+                |id| {
+                    offered.push(id.to_string());
+                    Ok(true)
+                },
+            )
+            .unwrap();
+        assert_eq!(id, base62(links + 1));
+        assert_eq!(offered, vec![id]);
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts() {
+        let mut offered = 0;
+        let result = generator(IdStrategy::Random).generate("https://example.com/", no_sequence, |_| {
+            offered += 1;
+            Ok(false)
+        });
+        assert!(result.is_err());
+        assert_eq!(offered, MAX_ID_ATTEMPTS);
+    }
+}
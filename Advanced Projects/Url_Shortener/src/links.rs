@@ -0,0 +1,322 @@
+// Authenticated management of the links owned by an API key: list, retarget and delete
+//
+// GET /links takes `q` (words searched in the short ID, title, tags and destination), `tag`, `created_after`
+// and `page`/`per_page`. The body stays a plain array; the total sits in X-Total-Count and neighbouring
+// pages are linked from the Link header.
+//
+// Every change of destination is kept as a numbered version, and POST /links/{id}/rollback makes an earlier
+// version's destination current again, itself recorded as a new version.
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::store::{self, LinkQuery, LinkStore, LinkUpdate, StoredLink};
+use crate::targeting::{self, Target};
+use crate::{auth, validation, AppState};
+
+const DEFAULT_PER_PAGE: usize = 50;
+const MAX_PER_PAGE: usize = 200;
+
+#[derive(Serialize)]
+struct LinkInfo {
+    id: String,
+    shortened_url: String,
+    original_url: String,
+    expires_at: Option<i64>,
+    max_clicks: Option<i64>,
+    click_count: i64,
+    created_at: Option<i64>,
+    warn_before_redirect: bool,
+    password_protected: bool,
+    failed_password_attempts: i64,
+    redirect_status: u16,
+    forward_query: bool,
+    targets: Vec<Target>,
+    title: Option<String>,
+    tags: Vec<String>,
+    note: Option<String>,
+}
+
+impl LinkInfo {
+    fn new(link: StoredLink, config: &Config) -> LinkInfo {
+        LinkInfo {
+            shortened_url: config.short_url(&link.id),
+            id: link.id,
+            original_url: link.original_url,
+            expires_at: link.expires_at,
+            max_clicks: link.max_clicks,
+            click_count: link.click_count,
+            created_at: link.created_at,
+            warn_before_redirect: link.warn_before_redirect,
+            password_protected: link.password_hash.is_some(),
+            failed_password_attempts: link.failed_password_attempts,
+            redirect_status: link.redirect_status,
+            forward_query: link.forward_query,
+            targets: link.targets,
+            title: link.title,
+            tags: link.tags,
+            note: link.note,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    q: Option<String>,
+    tag: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct RollbackPayload {
+    version: i64,
+}
+
+// Fields left out of the payload keep their current value
+#[derive(Deserialize)]
+pub struct UpdateLinkPayload {
+    original_url: Option<String>,
+    warn_before_redirect: Option<bool>,
+    redirect_status: Option<u16>,
+    forward_query: Option<bool>,
+    // Replaces all targets; an empty list removes them
+    targets: Option<Vec<Target>>,
+    // An empty title or note removes it, tags replace the current ones
+    title: Option<String>,
+    tags: Option<Vec<String>>,
+    note: Option<String>,
+}
+
+pub async fn list_links(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    params: web::Query<ListQuery>,
+) -> Result<HttpResponse, AppError> {
+    let params = params.into_inner();
+    let page = params.page.unwrap_or(1);
+    let per_page = params.per_page.unwrap_or(DEFAULT_PER_PAGE);
+    if page == 0 {
+        return Err(AppError::Validation("page starts at 1".to_string()));
+    }
+    if !(1..=MAX_PER_PAGE).contains(&per_page) {
+        return Err(AppError::Validation(format!("per_page must be between 1 and {}", MAX_PER_PAGE)));
+    }
+    let query = LinkQuery {
+        text: params.q.clone().filter(|q| !q.trim().is_empty()),
+        tag: params.tag.clone().filter(|tag| !tag.trim().is_empty()),
+        created_after: params.created_after.map(|t| t.timestamp()),
+        limit: per_page,
+        offset: (page - 1).saturating_mul(per_page),
+    };
+    let api_key = auth::bearer_token(&req);
+
+    let result = store::run(&data.store, move |store| {
+        let owner = auth::require_owner(store, api_key.as_deref())?;
+        store.list(owner, &query)
+    })
+    .await?;
+
+    let mut pages = Vec::new();
+    if page > 1 {
+        pages.push(format!("<{}>; rel=\"prev\"", page_url(&params, page - 1, per_page)));
+    }
+    if page.saturating_mul(per_page) < result.total {
+        pages.push(format!("<{}>; rel=\"next\"", page_url(&params, page + 1, per_page)));
+    }
+    let links: Vec<LinkInfo> = result.links.into_iter().map(|link| LinkInfo::new(link, &data.config)).collect();
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("X-Total-Count", result.total.to_string()));
+    if !pages.is_empty() {
+        response.insert_header(("Link", pages.join(", ")));
+    }
+    Ok(response.json(links))
+}
+
+// The listing URL for another page with the same filters
+fn page_url(params: &ListQuery, page: usize, per_page: usize) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    if let Some(q) = &params.q {
+        query.append_pair("q", q);
+    }
+    if let Some(tag) = &params.tag {
+        query.append_pair("tag", tag);
+    }
+    if let Some(created_after) = &params.created_after {
+        query.append_pair("created_after", &created_after.to_rfc3339());
+    }
+    query.append_pair("page", &page.to_string());
+    query.append_pair("per_page", &per_page.to_string());
+    format!("/links?{}", query.finish())
+}
+
+pub async fn update_link(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: web::Json<UpdateLinkPayload>,
+) -> Result<HttpResponse, AppError> {
+    let payload = payload.into_inner();
+    let policy = data.policy.current();
+    let original_url = match &payload.original_url {
+        Some(url) => {
+            let url = validation::normalize_url(url).map_err(AppError::Validation)?;
+            policy.enforce(&url)?;
+            Some(url)
+        }
+        None => None,
+    };
+    let targets = match payload.targets {
+        Some(mut targets) => {
+            targeting::validate_targets(&mut targets, &policy).map_err(AppError::Validation)?;
+            for target in &mut targets {
+                target.click_count = 0;
+            }
+            Some(targets)
+        }
+        None => None,
+    };
+    if let Some(status) = payload.redirect_status {
+        validation::validate_redirect_status(status).map_err(AppError::Validation)?;
+    }
+    // Normalized to "" rather than None, which would leave the current value in place
+    let title = match &payload.title {
+        Some(title) => Some(validation::normalize_title(title).map_err(AppError::Validation)?.unwrap_or_default()),
+        None => None,
+    };
+    let note = match &payload.note {
+        Some(note) => Some(validation::normalize_note(note).map_err(AppError::Validation)?.unwrap_or_default()),
+        None => None,
+    };
+    let tags = match &payload.tags {
+        Some(tags) => Some(validation::normalize_tags(tags).map_err(AppError::Validation)?),
+        None => None,
+    };
+    let update = LinkUpdate {
+        original_url,
+        warn_before_redirect: payload.warn_before_redirect,
+        redirect_status: payload.redirect_status,
+        forward_query: payload.forward_query,
+        targets,
+        title,
+        tags,
+        note,
+    };
+    if update.original_url.is_none()
+        && update.warn_before_redirect.is_none()
+        && update.redirect_status.is_none()
+        && update.forward_query.is_none()
+        && update.targets.is_none()
+        && update.title.is_none()
+        && update.tags.is_none()
+        && update.note.is_none()
+    {
+        return Err(AppError::Validation(
+            "Nothing to update, send original_url, warn_before_redirect, redirect_status, forward_query, targets, \
+             title, tags or note"
+                .to_string(),
+        ));
+    }
+    let api_key = auth::bearer_token(&req);
+    let id = id.into_inner();
+
+    let link = store::run(&data.store, move |store| {
+        let caller = authorize(store, api_key.as_deref(), &id)?;
+        store.update(&id, &update, caller)?.ok_or_else(|| not_found(&id))
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(LinkInfo::new(link, &data.config)))
+}
+
+pub async fn list_versions(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let api_key = auth::bearer_token(&req);
+    let id = id.into_inner();
+
+    let versions = store::run(&data.store, move |store| {
+        authorize(store, api_key.as_deref(), &id)?;
+        store.versions(&id)?.ok_or_else(|| not_found(&id))
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+pub async fn rollback_link(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    payload: web::Json<RollbackPayload>,
+) -> Result<HttpResponse, AppError> {
+    let api_key = auth::bearer_token(&req);
+    let id = id.into_inner();
+    let version = payload.version;
+    let policy = data.policy.current();
+
+    let link = store::run(&data.store, move |store| {
+        let caller = authorize(store, api_key.as_deref(), &id)?;
+        let versions = store.versions(&id)?.ok_or_else(|| not_found(&id))?;
+        let target = versions.into_iter().find(|v| v.version == version).ok_or_else(|| {
+            AppError::NotFound(format!("Link '{}' has no version {}", id, version))
+        })?;
+        // The policy may have changed since this destination was last in use
+        policy.enforce(&target.original_url)?;
+        let update = LinkUpdate {
+            original_url: Some(target.original_url),
+            warn_before_redirect: None,
+            redirect_status: None,
+            forward_query: None,
+            targets: None,
+            title: None,
+            tags: None,
+            note: None,
+        };
+        store.update(&id, &update, caller)?.ok_or_else(|| not_found(&id))
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().json(LinkInfo::new(link, &data.config)))
+}
+
+pub async fn delete_link(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let api_key = auth::bearer_token(&req);
+    let id = id.into_inner();
+
+    store::run(&data.store, move |store| {
+        authorize(store, api_key.as_deref(), &id)?;
+        store.delete(&id)
+    })
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Checks that the request carries a valid key and that the key owns the link; returns the key's owner ID
+fn authorize(store: &dyn LinkStore, api_key: Option<&str>, id: &str) -> Result<i64, AppError> {
+    let caller = auth::require_owner(store, api_key)?;
+
+    match store.resolve(id)? {
+        None => Err(not_found(id)),
+        Some(link) if link.owner_id != Some(caller) => {
+            Err(AppError::Forbidden(format!("Link '{}' is not owned by this API key", id)))
+        }
+        Some(_) => Ok(caller),
+    }
+}
+
+fn not_found(id: &str) -> AppError {
+    AppError::NotFound(format!("Link '{}' not found", id))
+}
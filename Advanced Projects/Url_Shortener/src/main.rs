@@ -1,16 +1,152 @@
 // URL Shortener in Rust using Actix-web and SQLite
 
 // curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com\"}" http://127.0.0.1:8080/shorten
+// curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com\", \"alias\": \"q3-report\"}" http://127.0.0.1:8080/shorten
+// curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com\", \"expires_at\": \"2030-01-01T00:00:00Z\", \"max_clicks\": 1}" http://127.0.0.1:8080/shorten
+// curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com\", \"warn_before_redirect\": true}" http://127.0.0.1:8080/shorten
+// curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com\", \"alias\": \"board-minutes\", \"password\": \"hunter22\"}" http://127.0.0.1:8080/shorten
+// curl -i -d "password=hunter22" http://127.0.0.1:8080/board-minutes
+// curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com/landing?ref=qr\", \"alias\": \"spring-sale\", \"redirect_status\": 301, \"forward_query\": true}" http://127.0.0.1:8080/shorten
+// curl -i "http://127.0.0.1:8080/spring-sale?utm_source=newsletter&utm_medium=email"
+// curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com/reports/2030-q1\", \"title\": \"Quarterly report\", \"tags\": [\"finance\", \"q1\"], \"note\": \"Printed on the flyer\"}" http://127.0.0.1:8080/shorten
+// curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com/app\", \"alias\": \"get-app\", \"targets\": [{\"url\": \"https://apps.apple.com/app/id1\", \"when\": {\"device\": \"ios\"}}, {\"url\": \"https://play.google.com/store/apps/details?id=com.example\", \"when\": {\"device\": \"android\"}}]}" http://127.0.0.1:8080/shorten
+// curl -X POST -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.com/a\", \"alias\": \"ab-test\", \"targets\": [{\"url\": \"https://www.example.com/a\", \"weight\": 70}, {\"url\": \"https://www.example.com/b\", \"weight\": 30}]}" http://127.0.0.1:8080/shorten
+// curl -X POST -H "Content-Type: text/csv" --data-binary @links.csv http://127.0.0.1:8080/shorten/batch
+// curl http://127.0.0.1:8080/q3-report/stats
+// curl http://127.0.0.1:8080/q3-report+
+// curl http://127.0.0.1:8080/cache
+// curl http://127.0.0.1:8080/metrics
+// curl -o q3-report.svg "http://127.0.0.1:8080/q3-report/qr?format=svg&size=512&margin=2&ec=H"
 
-use actix_web::{web, App, HttpServer, Responder, HttpResponse};
+// Or open http://127.0.0.1:8080/dashboard in a browser to shorten and manage links without curl
+
+// Settings come from url_shortener.toml (see config.example.toml), URL_SHORTENER_* environment variables and flags:
+// cargo run -- --bind 0.0.0.0:8080 --base-url https://sho.rt --database /var/lib/url_shortener.db
+// cargo run -- --storage memory
+
+// Schema migrations run automatically at startup; to see what would change first:
+// cargo run -- migrate --dry-run
+
+// API keys are managed from the command line and own the links created with them:
+// cargo run -- keys create --name marketing
+// curl -H "Authorization: Bearer us_..." http://127.0.0.1:8080/links
+// curl -H "Authorization: Bearer us_..." "http://127.0.0.1:8080/links?q=quarterly+report&tag=finance&created_after=2030-01-01T00:00:00Z&page=2&per_page=20"
+// curl -X PATCH -H "Authorization: Bearer us_..." -H "Content-Type: application/json" -d "{\"original_url\": \"https://www.example.org\"}" http://127.0.0.1:8080/links/q3-report
+// curl -H "Authorization: Bearer us_..." http://127.0.0.1:8080/links/q3-report/versions
+// curl -X POST -H "Authorization: Bearer us_..." -H "Content-Type: application/json" -d "{\"version\": 1}" http://127.0.0.1:8080/links/q3-report/rollback
+// curl -X DELETE -H "Authorization: Bearer us_..." http://127.0.0.1:8080/links/q3-report
+
+// Links can be exported and imported to move them between instances:
+// cargo run -- export --output links.jsonl
+// cargo run -- --database staging.db import links.jsonl --on-conflict rename
+
+// Generated short IDs are random by default; a base62 counter or a hash of the destination can be used instead:
+// cargo run -- --id-strategy counter
+// cargo run -- --id-strategy hash --id-length 7
+
+// Destinations can be restricted with a policy file (see policy.example.toml) that is reloaded on change:
+// cargo run -- --policy-file policy.toml
+// cargo run -- --policy-file policy.toml policy report
+
+mod analytics;
+mod auth;
+mod batch;
+mod config;
+mod dashboard;
+mod db;
+mod errors;
+mod ids;
+mod links;
+mod metrics;
+mod migrations;
+mod password;
+mod policy;
+mod preview;
+mod qr;
+mod rate_limit;
+mod store;
+mod targeting;
+mod transfer;
+mod validation;
+
+use config::Config;
+use errors::AppError;
+use metrics::{Metrics, RequestMetrics};
+use policy::{Policy, PolicyStore};
+use rate_limit::{KeyBy, RateLimit, RateLimiter};
+use store::cache::CachedStore;
+use store::{LinkStore, StoredLink};
+use targeting::Target;
+
+use actix_web::{web, App, HttpRequest, HttpServer, HttpResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::rt;
+use chrono::{DateTime, Utc};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use serde::{Deserialize, Serialize};
-use rusqlite::{params, Connection};
-use std::sync::Mutex;
-use nanoid::nanoid;
+use rusqlite::Connection;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often the background task deletes links past their expiry date
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// How often the policy file is checked for changes
+const POLICY_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Serialize, Deserialize)]
 struct UrlPayload {
     original_url: String,
+    alias: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    max_clicks: Option<i64>,
+    #[serde(default, deserialize_with = "validation::deserialize_flag")]
+    warn_before_redirect: bool,
+    password: Option<String>,
+    redirect_status: Option<u16>,
+    // Append the query string of each visit to the destination
+    #[serde(default, deserialize_with = "validation::deserialize_flag")]
+    forward_query: bool,
+    // Alternative destinations picked per visit, see targeting.rs
+    #[serde(default)]
+    targets: Vec<Target>,
+    title: Option<String>,
+    #[serde(default, deserialize_with = "validation::deserialize_tags")]
+    tags: Vec<String>,
+    note: Option<String>,
+}
+
+struct NewLink {
+    original_url: String,
+    alias: Option<String>,
+    owner_id: Option<i64>,
+    expires_at: Option<i64>,
+    max_clicks: Option<i64>,
+    warn_before_redirect: bool,
+    password_hash: Option<String>,
+    redirect_status: u16,
+    forward_query: bool,
+    targets: Vec<Target>,
+    title: Option<String>,
+    tags: Vec<String>,
+    note: Option<String>,
+}
+
+impl NewLink {
+    // Links without limits, protection, targets or descriptions are interchangeable with an identical
+    // existing one
+    fn is_reusable(&self) -> bool {
+        self.expires_at.is_none()
+            && self.max_clicks.is_none()
+            && self.password_hash.is_none()
+            && self.targets.is_empty()
+            && self.title.is_none()
+            && self.tags.is_empty()
+            && self.note.is_none()
+    }
 }
 
 #[derive(Serialize)]
@@ -18,62 +154,1046 @@ struct ShortenedUrl {
     shortened_url: String,
 }
 
+#[derive(Serialize)]
+struct CacheStatus {
+    enabled: bool,
+    #[serde(flatten)]
+    stats: Option<store::cache::CacheStats>,
+}
+
 struct AppState {
-    db_connection: Mutex<Connection>,
+    store: Arc<dyn LinkStore>,
+    // The same store as `store` when caching is enabled, kept for its hit/miss counters
+    cache: Option<Arc<CachedStore>>,
+    metrics: Arc<Metrics>,
+    config: Config,
+    policy: PolicyStore,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    
-    let conn = Connection::open("url_shortener.db").expect("Failed to connect to database");
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS urls (
-            id TEXT PRIMARY KEY,
-            original_url TEXT NOT NULL
-        )",
-        [],
-    ).expect("Failed to create table");
+    let matches = cli().get_matches();
+    let config = match Config::load(&matches) {
+        Ok(config) => config,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("Configuration error: {}", error);
+            }
+            process::exit(1);
+        }
+    };
 
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        if name == "migrate" && sub_matches.get_flag("dry-run") {
+            run_migrate_dry_run(&config);
+            return Ok(());
+        }
+        let mut conn = db::open(&config.database_path).expect("Failed to connect to database");
+        match name {
+            "migrate" => run_migrate_command(&mut conn, &config),
+            "keys" => run_keys_command(&mut conn, sub_matches),
+            "export" => run_export_command(&mut conn, sub_matches),
+            "import" => run_import_command(&mut conn, &config, sub_matches),
+            "policy" => run_policy_command(&mut conn, &config, sub_matches),
+            _ => unreachable!("clap only accepts known subcommands"),
+        }
+        return Ok(());
+    }
+
+    let policy = PolicyStore::new(config.policy_file.clone()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let mut store = open_store(&config);
+    let cache = CachedStore::new(store.clone(), &config.cache).map(Arc::new);
+    if let Some(cache) = &cache {
+        store = cache.clone();
+    }
     let state = web::Data::new(AppState {
-        db_connection: Mutex::new(conn),
+        store,
+        cache,
+        metrics: Metrics::new(),
+        config: config.clone(),
+        policy,
+    });
+
+    let sweep_state = state.clone();
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(EXPIRY_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match store::run(&sweep_state.store, |store| store.sweep_expired()).await {
+                Ok(0) => {}
+                Ok(n) => println!("Removed {} expired links", n),
+                Err(e) => eprintln!("Failed to remove expired links: {}", e),
+            }
+        }
     });
 
+    if let Some(path) = config.policy_file.clone() {
+        let reload_state = state.clone();
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(POLICY_RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                let state = reload_state.clone();
+                match web::block(move || state.policy.reload_if_changed()).await {
+                    Ok(Ok(true)) => println!("Reloaded destination policy from {}", path),
+                    Ok(Ok(false)) => {}
+                    Ok(Err(e)) => eprintln!("Keeping the previous destination policy: {}", e),
+                    Err(e) => eprintln!("Failed to reload destination policy: {}", e),
+                }
+            }
+        });
+    }
+
+    let limits = &config.rate_limit;
+    let create_limiter = RateLimiter::new(
+        limits.create_per_minute,
+        limits.create_burst,
+        KeyBy::ApiKeyOrIp(state.store.clone()),
+        limits.trust_forwarded_headers,
+    );
+    let redirect_limiter = RateLimiter::new(
+        limits.redirect_per_minute,
+        limits.redirect_burst,
+        KeyBy::Ip,
+        limits.trust_forwarded_headers,
+    );
+
     HttpServer::new(move || {
         App::new()
+            .wrap(RequestMetrics::new(state.metrics.clone()))
             .app_data(state.clone())
-            .route("/shorten", web::post().to(shorten_url))
-            .route("/{id}", web::get().to(redirect_url))
+            .configure(|cfg| routes(cfg, &create_limiter, &redirect_limiter))
     })
-    .bind("127.0.0.1:8080")?
+    .bind(&config.bind_address)?
     .run()
     .await
 }
 
-async fn shorten_url(data: web::Data<AppState>, payload: web::Json<UrlPayload>) -> impl Responder {
-    let id = nanoid!(8);
-    let original_url = &payload.original_url;
+// Every endpoint with its rate limits, shared by the server and the handler tests
+fn routes(cfg: &mut web::ServiceConfig, create_limiter: &Arc<RateLimiter>, redirect_limiter: &Arc<RateLimiter>) {
+    cfg.app_data(web::JsonConfig::default().error_handler(|err, _| AppError::Validation(err.to_string()).into()))
+        .app_data(web::QueryConfig::default().error_handler(|err, _| AppError::Validation(err.to_string()).into()))
+        .service(
+            web::resource("/shorten")
+                .wrap(RateLimit::new(create_limiter.clone()))
+                .route(web::post().to(shorten_url)),
+        )
+        .service(
+            web::resource("/shorten/batch")
+                .wrap(RateLimit::new(create_limiter.clone()))
+                .route(web::post().to(batch::shorten_batch)),
+        )
+        .route("/links", web::get().to(links::list_links))
+        .route("/links/{id}", web::patch().to(links::update_link))
+        .route("/links/{id}", web::delete().to(links::delete_link))
+        .route("/links/{id}/versions", web::get().to(links::list_versions))
+        .route("/links/{id}/rollback", web::post().to(links::rollback_link))
+        .route("/dashboard", web::get().to(dashboard::dashboard))
+        .route("/cache", web::get().to(cache_stats))
+        .route("/metrics", web::get().to(metrics::metrics))
+        .route("/{id}+", web::get().to(preview::preview_link))
+        .service(
+            web::resource("/{id}")
+                .wrap(RateLimit::new(redirect_limiter.clone()))
+                .route(web::get().to(redirect_url))
+                .route(web::post().to(password::unlock)),
+        )
+        .route("/{id}/stats", web::get().to(link_stats))
+        .route("/{id}/qr", web::get().to(qr::qr_code));
+}
+
+// Opens the configured storage backend, bringing the database schema up to date first
+fn open_store(config: &Config) -> Arc<dyn LinkStore> {
+    match config.storage {
+        store::Backend::Sqlite => {
+            let mut conn = db::open(&config.database_path).expect("Failed to connect to database");
+            match migrations::run(&mut conn) {
+                Ok(applied) => {
+                    for migration in applied {
+                        println!("Applied migration {}: {}", migration.version, migration.description);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+            let ip_salt = analytics::load_ip_salt(&conn).expect("Failed to read the IP hash salt");
+            drop(conn);
+
+            let pool = db::pool(&config.database_path, config.db_pool_size).expect("Failed to create database pool");
+            Arc::new(store::sqlite::SqliteStore::new(pool, config.clone(), ip_salt))
+        }
+        store::Backend::Memory => {
+            println!("Keeping links in memory, they will be lost when the server stops");
+            Arc::new(store::memory::MemoryStore::new(config.clone()))
+        }
+    }
+}
+
+fn cli() -> Command {
+    Command::new("Url_Shortener")
+        .about("URL shortener service backed by SQLite")
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .global(true)
+                .value_name("FILE")
+                .help("Path to a TOML config file (default: url_shortener.toml if present)"),
+        )
+        .arg(
+            Arg::new("bind")
+                .long("bind")
+                .global(true)
+                .value_name("ADDR")
+                .help("Address to listen on, e.g. 127.0.0.1:8080"),
+        )
+        .arg(
+            Arg::new("base-url")
+                .long("base-url")
+                .global(true)
+                .value_name("URL")
+                .help("Public base URL used when building short links"),
+        )
+        .arg(
+            Arg::new("storage")
+                .long("storage")
+                .global(true)
+                .value_name("BACKEND")
+                .value_parser(["sqlite", "memory"])
+                .help("Where links are stored; memory keeps nothing across restarts"),
+        )
+        .arg(
+            Arg::new("database")
+                .long("database")
+                .global(true)
+                .value_name("PATH")
+                .help("Path to the SQLite database file"),
+        )
+        .arg(
+            Arg::new("id-strategy")
+                .long("id-strategy")
+                .global(true)
+                .value_name("STRATEGY")
+                .value_parser(["random", "counter", "hash"])
+                .help("How short IDs are generated: random characters, a base62 counter or a hash of the URL"),
+        )
+        .arg(
+            Arg::new("id-length")
+                .long("id-length")
+                .global(true)
+                .value_name("N")
+                .value_parser(value_parser!(usize))
+                .help("Length of generated short IDs"),
+        )
+        .arg(
+            Arg::new("id-alphabet")
+                .long("id-alphabet")
+                .global(true)
+                .value_name("CHARS")
+                .help("Characters used for generated short IDs"),
+        )
+        .arg(
+            Arg::new("require-api-key")
+                .long("require-api-key")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Reject /shorten requests without an API key"),
+        )
+        .arg(
+            Arg::new("policy-file")
+                .long("policy-file")
+                .global(true)
+                .value_name("FILE")
+                .help("TOML file of blocked and allowed destinations"),
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about("Apply pending database schema migrations and exit")
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Only print the migrations that would be applied"),
+                ),
+        )
+        .subcommand(
+            Command::new("keys")
+                .about("Manage API keys")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("Create a new API key and print it")
+                        .arg(
+                            Arg::new("name")
+                                .long("name")
+                                .value_name("NAME")
+                                .required(true)
+                                .help("Who or what the key is for"),
+                        ),
+                )
+                .subcommand(Command::new("list").about("List API keys"))
+                .subcommand(
+                    Command::new("revoke").about("Revoke an API key").arg(
+                        Arg::new("id")
+                            .value_name("ID")
+                            .required(true)
+                            .value_parser(value_parser!(i64))
+                            .help("ID of the key, as shown by 'keys list'"),
+                    ),
+                ),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Write all links with their metadata and click counts to a file")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("File to write to (default: standard output)"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .value_parser(["jsonl", "csv"])
+                        .help("Output format (default: csv for .csv files, otherwise jsonl)"),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Load links from a file written by 'export'")
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("File to read, or - for standard input"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .value_parser(["jsonl", "csv"])
+                        .help("Input format (default: csv for .csv files, otherwise jsonl)"),
+                )
+                .arg(
+                    Arg::new("on-conflict")
+                        .long("on-conflict")
+                        .value_name("POLICY")
+                        .value_parser(["skip", "overwrite", "rename"])
+                        .default_value("skip")
+                        .help("What to do with links whose ID is already taken"),
+                )
+                .arg(
+                    Arg::new("owner")
+                        .long("owner")
+                        .value_name("KEY_ID")
+                        .value_parser(value_parser!(i64))
+                        .help("API key that owns the imported links, as shown by 'keys list'"),
+                ),
+        )
+        .subcommand(
+            Command::new("policy")
+                .about("Inspect the destination policy")
+                .subcommand_required(true)
+                .subcommand(Command::new("report").about("List stored links whose destination the policy refuses")),
+        )
+}
+
+fn run_keys_command(conn: &mut Connection, matches: &ArgMatches) {
+    if let Err(e) = migrations::run(conn) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    match matches.subcommand() {
+        Some(("create", sub_matches)) => {
+            let name = sub_matches.get_one::<String>("name").unwrap();
+            let (id, key) = auth::create_key(conn, name).expect("Failed to create API key");
+            println!("Created API key {} for '{}':", id, name);
+            println!("{}", key);
+            println!("Store it now, it cannot be shown again.");
+        }
+        Some(("list", _)) => {
+            let keys = auth::list_keys(conn).expect("Failed to list API keys");
+            if keys.is_empty() {
+                println!("No API keys");
+            }
+            for key in keys {
+                let created = DateTime::from_timestamp(key.created_at, 0).unwrap_or_default();
+                let status = if key.revoked { "revoked" } else { "active" };
+                println!("{:>4}  {:<8} {}  {}", key.id, status, created.format("%Y-%m-%d %H:%M"), key.name);
+            }
+        }
+        Some(("revoke", sub_matches)) => {
+            let id = *sub_matches.get_one::<i64>("id").unwrap();
+            if auth::revoke_key(conn, id).expect("Failed to revoke API key") {
+                println!("Revoked API key {}", id);
+            } else {
+                eprintln!("No active API key with ID {}", id);
+                process::exit(1);
+            }
+        }
+        _ => unreachable!("clap requires a keys subcommand"),
+    }
+}
+
+fn run_export_command(conn: &mut Connection, matches: &ArgMatches) {
+    if let Err(e) = migrations::run(conn) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    let output = matches.get_one::<String>("output");
+    let format = match matches.get_one::<String>("format") {
+        Some(name) => transfer::Format::from_name(name).unwrap(),
+        None => transfer::Format::from_path(output.map_or("", String::as_str)),
+    };
+    let result = match output {
+        Some(path) => fs::File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path, e))
+            .and_then(|file| transfer::export_links(conn, format, io::BufWriter::new(file))),
+        None => transfer::export_links(conn, format, io::stdout().lock()),
+    };
+    match (result, output) {
+        (Ok(count), Some(path)) => println!("Exported {} links to {}", count, path),
+        (Ok(_), None) => {}
+        (Err(e), _) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_import_command(conn: &mut Connection, config: &Config, matches: &ArgMatches) {
+    if let Err(e) = migrations::run(conn) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 
-    {
-        let conn = data.db_connection.lock().unwrap();
-        conn.execute(
-            "INSERT INTO urls (id, original_url) VALUES (?1, ?2)",
-            params![id, original_url],
-        ).expect("Failed to insert into database");
+    let path = matches.get_one::<String>("file").unwrap();
+    let format = match matches.get_one::<String>("format") {
+        Some(name) => transfer::Format::from_name(name).unwrap(),
+        None => transfer::Format::from_path(path),
+    };
+    let policy = transfer::ConflictPolicy::from_name(matches.get_one::<String>("on-conflict").unwrap()).unwrap();
+    let owner_id = matches.get_one::<i64>("owner").copied();
+    if let Some(owner_id) = owner_id {
+        if !auth::is_active_key(conn, owner_id).expect("Failed to look up API key") {
+            eprintln!("No active API key with ID {}", owner_id);
+            process::exit(1);
+        }
     }
 
-    let shortened_url = format!("http://127.0.0.1:8080/{}", id);
-    HttpResponse::Ok().json(ShortenedUrl { shortened_url })
+    let destinations = match &config.policy_file {
+        Some(policy_file) => Policy::load(policy_file).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        }),
+        None => Policy::default(),
+    };
+    let records = if path == "-" {
+        transfer::read_records(io::stdin().lock(), format, &destinations)
+    } else {
+        match fs::File::open(path) {
+            Ok(file) => transfer::read_records(file, format, &destinations),
+            Err(e) => Err(vec![format!("Failed to open {}: {}", path, e)]),
+        }
+    };
+    let records = records.unwrap_or_else(|errors| {
+        for error in errors {
+            eprintln!("{}", error);
+        }
+        eprintln!("Nothing was imported");
+        process::exit(1);
+    });
+
+    let summary = transfer::import_links(conn, config, records, policy, owner_id).unwrap_or_else(|e| {
+        eprintln!("Import failed, nothing was imported: {}", e);
+        process::exit(1);
+    });
+    for (old_id, new_id) in &summary.renamed {
+        println!("Renamed {} to {}", old_id, new_id);
+    }
+    println!(
+        "Imported {} links ({} skipped, {} overwritten, {} renamed)",
+        summary.imported + summary.overwritten + summary.renamed.len(),
+        summary.skipped,
+        summary.overwritten,
+        summary.renamed.len()
+    );
 }
 
-async fn redirect_url(data: web::Data<AppState>, id: web::Path<String>) -> impl Responder {
-    let conn = data.db_connection.lock().unwrap();
-    let mut stmt = conn.prepare("SELECT original_url FROM urls WHERE id = ?1").expect("Failed to prepare query");
+fn run_policy_command(conn: &mut Connection, config: &Config, matches: &ArgMatches) {
+    if let Err(e) = migrations::run(conn) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    let path = config.policy_file.as_deref().unwrap_or_else(|| {
+        eprintln!("No policy file configured, set policy_file or pass --policy-file");
+        process::exit(1);
+    });
+    let policy = Policy::load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
-    let original_url: Option<String> = stmt.query_row(params![id.as_str()], |row| row.get(0)).ok();
+    match matches.subcommand() {
+        Some(("report", _)) => {
+            let affected = policy.affected_links(conn).expect("Failed to read links");
+            if affected.is_empty() {
+                println!("No links are affected by {}", path);
+            }
+            for link in &affected {
+                println!("{}  {} clicks  {}", link.id, link.click_count, link.destination);
+                println!("    {}", link.reason);
+            }
+            if !affected.is_empty() {
+                println!("{} link(s) refused by {}", affected.len(), path);
+            }
+        }
+        _ => unreachable!("clap requires a policy subcommand"),
+    }
+}
 
-    if let Some(url) = original_url {
-        HttpResponse::Found().append_header(("Location", url)).finish()
+// Opens the database read-only, so a dry run never creates the file or switches it to WAL
+fn run_migrate_dry_run(config: &Config) {
+    let conn = if Path::new(&config.database_path).exists() {
+        db::open_read_only(&config.database_path).expect("Failed to connect to database")
+    } else {
+        // A missing file would start at version 0, the same as an empty in-memory database
+        Connection::open_in_memory().expect("Failed to open an in-memory database")
+    };
+    let pending = migrations::pending(&conn).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let current = migrations::current_version(&conn).expect("Failed to read schema version");
+    if pending.is_empty() {
+        println!("{} is up to date at schema version {}", config.database_path, current);
     } else {
-        HttpResponse::NotFound().body("URL not found")
+        println!("{} is at schema version {}, {} pending migration(s):", config.database_path, current, pending.len());
+        for migration in pending {
+            println!("  {}: {}", migration.version, migration.description);
+        }
+    }
+}
+
+fn run_migrate_command(conn: &mut Connection, config: &Config) {
+    match migrations::run(conn) {
+        Ok(applied) if applied.is_empty() => {
+            println!("{} is up to date at schema version {}", config.database_path, migrations::latest_version());
+        }
+        Ok(applied) => {
+            for migration in applied {
+                println!("Applied migration {}: {}", migration.version, migration.description);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+async fn shorten_url(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    payload: web::Json<UrlPayload>,
+) -> Result<HttpResponse, AppError> {
+    let payload = payload.into_inner();
+    let policy = data.policy.current();
+    let api_key = auth::bearer_token(&req);
+    let config = data.config.clone();
+    let metrics = data.metrics.clone();
+
+    // Validation runs on the blocking pool too, since hashing a password takes a while
+    let created = store::run(&data.store, move |store| {
+        let mut link = prepare_link(&payload, &policy)?;
+        link.owner_id = request_owner(store, &config, api_key.as_deref())?;
+        metrics.time_db("create", || store.create(&link))
+    })
+    .await?;
+    // A reused link was counted when it was first created
+    if created.inserted {
+        data.metrics.links_created.inc();
+    }
+
+    let shortened_url = data.config.short_url(&created.id);
+    Ok(HttpResponse::Ok().json(ShortenedUrl { shortened_url }))
+}
+
+// Validates a payload and turns it into a link ready for insertion, still without an owner
+fn prepare_link(payload: &UrlPayload, policy: &Policy) -> Result<NewLink, AppError> {
+    if matches!(payload.expires_at, Some(expires_at) if expires_at <= Utc::now()) {
+        return Err(AppError::Validation("expires_at must be in the future".to_string()));
+    }
+    if matches!(payload.max_clicks, Some(n) if n < 1) {
+        return Err(AppError::Validation("max_clicks must be at least 1".to_string()));
+    }
+    let original_url = validation::normalize_url(&payload.original_url).map_err(AppError::Validation)?;
+    policy.enforce(&original_url)?;
+    if let Some(alias) = &payload.alias {
+        validation::validate_alias(alias).map_err(AppError::Validation)?;
+    }
+    let password_hash = match &payload.password {
+        Some(password) => {
+            validation::validate_password(password).map_err(AppError::Validation)?;
+            Some(password::hash(password)?)
+        }
+        None => None,
+    };
+    let redirect_status = payload.redirect_status.unwrap_or(validation::DEFAULT_REDIRECT_STATUS);
+    validation::validate_redirect_status(redirect_status).map_err(AppError::Validation)?;
+    let mut targets = payload.targets.clone();
+    targeting::validate_targets(&mut targets, policy).map_err(AppError::Validation)?;
+    for target in &mut targets {
+        target.click_count = 0;
+    }
+    let title = match &payload.title {
+        Some(title) => validation::normalize_title(title).map_err(AppError::Validation)?,
+        None => None,
+    };
+    let note = match &payload.note {
+        Some(note) => validation::normalize_note(note).map_err(AppError::Validation)?,
+        None => None,
+    };
+    let tags = validation::normalize_tags(&payload.tags).map_err(AppError::Validation)?;
+
+    Ok(NewLink {
+        original_url,
+        alias: payload.alias.clone(),
+        owner_id: None,
+        expires_at: payload.expires_at.map(|t| t.timestamp()),
+        max_clicks: payload.max_clicks,
+        warn_before_redirect: payload.warn_before_redirect,
+        password_hash,
+        redirect_status,
+        forward_query: payload.forward_query,
+        targets,
+        title,
+        tags,
+        note,
+    })
+}
+
+// Resolves the API key of a link-creating request to its owner, enforcing require_api_key
+fn request_owner(store: &dyn LinkStore, config: &Config, api_key: Option<&str>) -> Result<Option<i64>, AppError> {
+    let owner_id = auth::optional_owner(store, api_key)?;
+    if owner_id.is_none() && config.require_api_key {
+        return Err(auth::missing_key());
+    }
+    Ok(owner_id)
+}
+
+enum Visit {
+    // Boxed, a link is far larger than the other variant
+    Allowed(Box<StoredLink>),
+    PasswordRequired,
+}
+
+// Errors are negotiated against Accept, so people following a dead link in a browser get a page
+async fn redirect_url(req: HttpRequest, data: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    let id = id.into_inner();
+    let click = click_from(&req, &data.config);
+    let policy = data.policy.current();
+    let link_id = id.clone();
+    let metrics = data.metrics.clone();
+    let visit = store::run(&data.store, move |store| {
+        metrics.time_db("redirect", || visit_link(store, &link_id, &click, &policy, false))
+    })
+    .await;
+    data.metrics.observe_visit(visit.as_ref());
+
+    match visit {
+        Ok(Visit::Allowed(mut link)) => {
+            // The interstitial shows the same destination the redirect would lead to
+            link.original_url = link.destination(req.query_string());
+            if link.warn_before_redirect {
+                return preview::page(&data.config, &link, true);
+            }
+            let status = StatusCode::from_u16(link.redirect_status).unwrap_or(StatusCode::FOUND);
+            HttpResponse::build(status).append_header((header::LOCATION, link.original_url)).finish()
+        }
+        Ok(Visit::PasswordRequired) => password::form_page(&data.config, &id, req.query_string(), None),
+        Err(e) => e.negotiated_response(&req),
+    }
+}
+
+fn click_from(req: &HttpRequest, config: &Config) -> analytics::Click {
+    analytics::Click {
+        referrer: header_str(req, header::REFERER).map(str::to_string),
+        user_agent: header_str(req, header::USER_AGENT).map(str::to_string),
+        client_ip: rate_limit::client_ip(&req.connection_info(), config.rate_limit.trust_forwarded_headers)
+            .map(str::to_string),
+        accept_language: header_str(req, header::ACCEPT_LANGUAGE).map(str::to_string),
+    }
+}
+
+// Counts the visit even when an interstitial is shown, so max_clicks caps how often the destination is revealed.
+// Password-protected links are only counted once `unlocked` says the visitor gave the right password.
+fn visit_link(
+    store: &dyn LinkStore,
+    id: &str,
+    click: &analytics::Click,
+    policy: &Policy,
+    unlocked: bool,
+) -> Result<Visit, AppError> {
+    let mut link = store.resolve(id)?.ok_or_else(|| not_found(id))?;
+    if !link.is_usable(Utc::now().timestamp()) {
+        return Err(gone(id));
+    }
+    if link.password_hash.is_some() && !unlocked {
+        return Ok(Visit::PasswordRequired);
+    }
+    // From here on original_url is where this visitor goes
+    let target = targeting::choose(&link.targets, id, click);
+    if let Some(i) = target {
+        link.original_url = link.targets[i].url.clone();
+    }
+    if policy.check(&link.original_url).is_some() {
+        return Err(blocked(id));
+    }
+    // Another visitor may have used up the last click since the link was read
+    if !store.record_hit(id, click, target)? {
+        return Err(gone(id));
+    }
+    link.click_count += 1;
+    if let Some(i) = target {
+        link.targets[i].click_count += 1;
+    }
+    Ok(Visit::Allowed(Box::new(link)))
+}
+
+async fn link_stats(data: web::Data<AppState>, id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let id = id.into_inner();
+    let stats = store::run(&data.store, move |store| store.stats(&id)?.ok_or_else(|| not_found(&id))).await?;
+
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+async fn cache_stats(data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    let stats = data.cache.as_ref().map(|cache| cache.stats());
+    Ok(HttpResponse::Ok().json(CacheStatus { enabled: stats.is_some(), stats }))
+}
+
+fn not_found(id: &str) -> AppError {
+    AppError::NotFound(format!("Short link '{}' not found", id))
+}
+
+fn gone(id: &str) -> AppError {
+    AppError::Gone(format!("Short link '{}' has expired", id))
+}
+
+fn blocked(id: &str) -> AppError {
+    AppError::Forbidden(format!("Short link '{}' leads to a blocked destination", id))
+}
+
+fn header_str(req: &HttpRequest, name: header::HeaderName) -> Option<&str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+    use actix_web::test;
+    use serde_json::{json, Value};
+    use store::memory::MemoryStore;
+
+    const OWNER_KEY: &str = "us_owner";
+    const OTHER_KEY: &str = "us_other";
+
+    fn test_state() -> web::Data<AppState> {
+        state_with(Config { storage: store::Backend::Memory, ..Config::default() })
+    }
+
+    fn state_with(config: Config) -> web::Data<AppState> {
+        let store = Arc::new(MemoryStore::new(config.clone()));
+        store.add_api_key(OWNER_KEY, 1);
+        store.add_api_key(OTHER_KEY, 2);
+        web::Data::new(AppState {
+            store,
+            cache: None,
+            metrics: Metrics::new(),
+            config,
+            policy: PolicyStore::new(None).unwrap(),
+        })
+    }
+
+    // Rate limits are off, a per_minute of 0 disables a limiter
+    fn test_app(
+        state: web::Data<AppState>,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        limited_app(state, RateLimiter::new(0, 1, KeyBy::Ip, false))
+    }
+
+    fn limited_app(
+        state: web::Data<AppState>,
+        create_limiter: Arc<RateLimiter>,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        let redirect_limiter = RateLimiter::new(0, 1, KeyBy::Ip, false);
+        App::new().app_data(state).configure(move |cfg| routes(cfg, &create_limiter, &redirect_limiter))
+    }
+
+    fn shorten(body: Value, key: Option<&str>) -> test::TestRequest {
+        let req = test::TestRequest::post().uri("/shorten").set_json(body);
+        match key {
+            Some(key) => req.insert_header((header::AUTHORIZATION, format!("Bearer {}", key))),
+            None => req,
+        }
+    }
+
+    fn short_id(body: &Value) -> String {
+        body["shortened_url"].as_str().unwrap().rsplit('/').next().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn test_create_and_redirect() {
+        let app = test::init_service(test_app(test_state())).await;
+
+        let req = shorten(json!({"original_url": "https://example.com/page"}), None).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let id = short_id(&body);
+
+        let req = test::TestRequest::get().uri(&format!("/{}", id)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), "https://example.com/page");
+    }
+
+    #[actix_web::test]
+    async fn test_reused_link_is_not_counted() {
+        let state = test_state();
+        let app = test::init_service(test_app(state.clone())).await;
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let req = shorten(json!({"original_url": "https://example.com/same"}), None).to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+            ids.push(short_id(&body));
+        }
+        let req = test::TestRequest::post()
+            .uri("/shorten/batch")
+            .set_json(json!([
+                {"original_url": "https://example.com/same"},
+                {"original_url": "https://example.com/new"},
+            ]))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        assert_eq!(ids[0], ids[1]);
+        assert_eq!(state.metrics.links_created.get(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_forwarded_for_does_not_pick_target() {
+        let app = test::init_service(test_app(test_state())).await;
+
+        let targets = json!([{"url": "https://example.com/a"}, {"url": "https://example.com/b"}]);
+        let req = shorten(json!({"original_url": "https://example.com/", "targets": targets}), None).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let id = short_id(&body);
+
+        let mut locations = std::collections::HashSet::new();
+        for i in 0..20 {
+            let req = test::TestRequest::get()
+                .uri(&format!("/{}", id))
+                .peer_addr("203.0.113.7:40000".parse().unwrap())
+                .insert_header(("X-Forwarded-For", format!("198.51.100.{}", i)))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            locations.insert(resp.headers().get(header::LOCATION).unwrap().to_str().unwrap().to_string());
+        }
+        assert_eq!(locations.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_batch_rows_are_rate_limited() {
+        let app = test::init_service(limited_app(test_state(), RateLimiter::new(1, 5, KeyBy::Ip, false))).await;
+        let batch = |rows: usize| {
+            let links: Vec<Value> =
+                (0..rows).map(|i| json!({"original_url": format!("https://example.com/{}", i)})).collect();
+            test::TestRequest::post().uri("/shorten/batch").set_json(links).to_request()
+        };
+
+        // More rows than the burst can never be afforded
+        assert_eq!(test::call_service(&app, batch(6)).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        let resp = test::call_service(&app, batch(3)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        assert_eq!(test::call_service(&app, batch(2)).await.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let req = shorten(json!({"original_url": "https://example.com/"}), None).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn test_stats_hide_target_urls() {
+        let app = test::init_service(test_app(test_state())).await;
+
+        let targets = json!([{"url": "https://example.com/secret-a"}, {"url": "https://example.com/secret-b"}]);
+        let payload = json!({"original_url": "https://example.com/", "password": "hunter2", "targets": targets});
+        let body: Value = test::call_and_read_body_json(&app, shorten(payload, None).to_request()).await;
+        let id = short_id(&body);
+
+        let req = test::TestRequest::get().uri(&format!("/{}/stats", id)).to_request();
+        let stats: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(stats["targets"], json!([{"position": 0, "clicks": 0}, {"position": 1, "clicks": 0}]));
+        assert!(!stats.to_string().contains("secret"));
+    }
+
+    #[actix_web::test]
+    async fn test_csv_tags_keep_cell_text() {
+        let app = test::init_service(test_app(test_state())).await;
+
+        let csv = "original_url,tags\n\
+                   https://example.com/a,nan\n\
+                   https://example.com/b,1e5\n\
+                   https://example.com/c,\"007,inf\"\n";
+        let req = test::TestRequest::post()
+            .uri("/shorten/batch")
+            .insert_header((header::CONTENT_TYPE, "text/csv"))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", OWNER_KEY)))
+            .set_payload(csv)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/links")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", OWNER_KEY)))
+            .to_request();
+        let links: Value = test::call_and_read_body_json(&app, req).await;
+        let tags: Vec<Value> = links.as_array().unwrap().iter().map(|link| link["tags"].clone()).collect();
+        assert_eq!(tags, vec![json!(["nan"]), json!(["1e5"]), json!(["007", "inf"])]);
+    }
+
+    #[actix_web::test]
+    async fn test_hash_ids_for_one_url_never_run_out() {
+        let config =
+            Config { storage: store::Backend::Memory, id_strategy: ids::IdStrategy::Hash, ..Config::default() };
+        let app = test::init_service(test_app(state_with(config))).await;
+
+        // One-time links to the same file can't reuse each other, so each one needs a fresh ID
+        let mut ids = std::collections::HashSet::new();
+        for _ in 0..12 {
+            let req = shorten(json!({"original_url": "https://example.com/dl", "max_clicks": 1}), None).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body: Value = test::read_body_json(resp).await;
+            ids.insert(short_id(&body));
+        }
+        assert_eq!(ids.len(), 12);
+    }
+
+    #[actix_web::test]
+    async fn test_alias_conflict() {
+        let app = test::init_service(test_app(test_state())).await;
+
+        let req = shorten(json!({"original_url": "https://example.com/a", "alias": "docs"}), None).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(short_id(&body), "docs");
+
+        let req = shorten(json!({"original_url": "https://example.com/b", "alias": "docs"}), None).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn test_gone_after_max_clicks() {
+        let app = test::init_service(test_app(test_state())).await;
+
+        let req = shorten(json!({"original_url": "https://example.com/", "max_clicks": 1}), None).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let id = short_id(&body);
+
+        let req = test::TestRequest::get().uri(&format!("/{}", id)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FOUND);
+        let req = test::TestRequest::get().uri(&format!("/{}", id)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::GONE);
+    }
+
+    #[actix_web::test]
+    async fn test_list_requires_valid_key() {
+        let app = test::init_service(test_app(test_state())).await;
+
+        let req = shorten(json!({"original_url": "https://example.com/mine"}), Some(OWNER_KEY)).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let id = short_id(&body);
+
+        let req = test::TestRequest::get().uri("/links").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get()
+            .uri("/links")
+            .insert_header((header::AUTHORIZATION, "Bearer us_unknown"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/links")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", OWNER_KEY)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(&id));
+
+        let req = test::TestRequest::get()
+            .uri("/links")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", OTHER_KEY)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(!body.contains(&id));
+    }
+
+    #[actix_web::test]
+    async fn test_delete_requires_owner() {
+        let app = test::init_service(test_app(test_state())).await;
+
+        let req = shorten(json!({"original_url": "https://example.com/mine"}), Some(OWNER_KEY)).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let id = short_id(&body);
+        let uri = format!("/links/{}", id);
+
+        let req = test::TestRequest::delete().uri(&uri).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", OTHER_KEY)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::delete()
+            .uri(&uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", OWNER_KEY)))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::get().uri(&format!("/{}", id)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
     }
 }
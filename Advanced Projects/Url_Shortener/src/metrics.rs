@@ -0,0 +1,161 @@
+// Prometheus metrics, served in the text exposition format at GET /metrics
+//
+// Every request is counted and timed by the `RequestMetrics` middleware, labelled with the matched route
+// pattern (e.g. "/{id}") rather than the raw path so short IDs don't each get their own series.
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error, HttpResponse};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::errors::AppError;
+use crate::{AppState, Visit};
+
+// Storage calls are mostly sub-millisecond, so the buckets start lower than prometheus' defaults
+const DB_BUCKETS: &[f64] = &[0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    pub links_created: IntCounter,
+    redirects: IntCounter,
+    not_found: IntCounter,
+    db_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Metrics> {
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route, method and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route, method and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let links_created = IntCounter::new("links_created_total", "Short links created").unwrap();
+        let redirects = IntCounter::new("redirects_total", "Visitors sent on to a link's destination").unwrap();
+        let not_found = IntCounter::new("not_found_total", "Lookups of short links that don't exist").unwrap();
+        let db_duration = HistogramVec::new(
+            HistogramOpts::new("db_query_duration_seconds", "Time spent in the link store by operation")
+                .buckets(DB_BUCKETS.to_vec()),
+            &["operation"],
+        )
+        .unwrap();
+
+        let registry = Registry::new_custom(Some("url_shortener".to_string()), None).unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_duration.clone())).unwrap();
+        registry.register(Box::new(links_created.clone())).unwrap();
+        registry.register(Box::new(redirects.clone())).unwrap();
+        registry.register(Box::new(not_found.clone())).unwrap();
+        registry.register(Box::new(db_duration.clone())).unwrap();
+
+        Arc::new(Metrics { registry, requests, request_duration, links_created, redirects, not_found, db_duration })
+    }
+
+    // Times `f` as a storage operation; meant to run inside the blocking closure passed to `store::run`
+    pub fn time_db<T>(&self, operation: &str, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.db_duration.with_label_values(&[operation]).observe(started.elapsed().as_secs_f64());
+        result
+    }
+
+    // Counts the outcome of following a short link
+    pub fn observe_visit(&self, visit: Result<&Visit, &AppError>) {
+        match visit {
+            Ok(Visit::Allowed(_)) => self.redirects.inc(),
+            Ok(Visit::PasswordRequired) => {}
+            Err(AppError::NotFound(_)) => self.not_found.inc(),
+            Err(_) => {}
+        }
+    }
+
+    fn observe_request(&self, route: &str, method: &str, status: &str, seconds: f64) {
+        self.requests.with_label_values(&[route, method, status]).inc();
+        self.request_duration.with_label_values(&[route, method, status]).observe(seconds);
+    }
+
+    fn render(&self) -> Result<String, AppError> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| AppError::Internal(format!("Failed to encode metrics: {}", e)))?;
+        String::from_utf8(buffer).map_err(|e| AppError::Internal(format!("Failed to encode metrics: {}", e)))
+    }
+}
+
+// GET /metrics
+pub async fn metrics(data: web::Data<AppState>) -> Result<HttpResponse, AppError> {
+    let body = data.metrics.render()?;
+    Ok(HttpResponse::Ok().content_type(prometheus::TEXT_FORMAT).body(body))
+}
+
+pub struct RequestMetrics {
+    metrics: Arc<Metrics>,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: Arc<Metrics>) -> RequestMetrics {
+        RequestMetrics { metrics }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service: Rc::new(service), metrics: self.metrics.clone() }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let metrics = self.metrics.clone();
+        let started = Instant::now();
+        let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+        let method = req.method().to_string();
+
+        Box::pin(async move {
+            let result = service.call(req).await;
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            metrics.observe_request(&route, &method, status.as_str(), started.elapsed().as_secs_f64());
+            result
+        })
+    }
+}
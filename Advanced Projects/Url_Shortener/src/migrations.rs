@@ -0,0 +1,275 @@
+// Versioned schema changes for url_shortener.db
+//
+// Every change to the schema is appended to MIGRATIONS with the next version number and never edited
+// afterwards. Pending migrations are applied in order inside a single transaction, so a failure leaves
+// the database at its previous version.
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create urls table",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS urls (
+                    id TEXT PRIMARY KEY,
+                    original_url TEXT NOT NULL
+                );",
+            )
+        },
+    },
+    Migration {
+        version: 2,
+        description: "add expiry, click limit and click count to urls",
+        // Databases created before versioning may already have some of these columns
+        apply: |conn| {
+            add_column_if_missing(conn, "urls", "expires_at", "INTEGER")?;
+            add_column_if_missing(conn, "urls", "max_clicks", "INTEGER")?;
+            add_column_if_missing(conn, "urls", "click_count", "INTEGER NOT NULL DEFAULT 0")
+        },
+    },
+    Migration {
+        version: 3,
+        description: "index urls by original_url",
+        apply: |conn| conn.execute_batch("CREATE INDEX IF NOT EXISTS idx_urls_original_url ON urls (original_url);"),
+    },
+    Migration {
+        version: 4,
+        description: "create clicks table",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS clicks (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    url_id TEXT NOT NULL,
+                    clicked_at INTEGER NOT NULL,
+                    referrer TEXT,
+                    user_agent TEXT,
+                    ip_hash TEXT
+                );
+                CREATE INDEX IF NOT EXISTS idx_clicks_url_id ON clicks (url_id);",
+            )
+        },
+    },
+    Migration {
+        version: 5,
+        description: "create api_keys table and link owners",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE api_keys (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    name TEXT NOT NULL,
+                    key_hash TEXT NOT NULL UNIQUE,
+                    created_at INTEGER NOT NULL,
+                    revoked_at INTEGER
+                );
+                ALTER TABLE urls ADD COLUMN owner_id INTEGER REFERENCES api_keys (id);
+                CREATE INDEX idx_urls_owner_id ON urls (owner_id);",
+            )
+        },
+    },
+    Migration {
+        version: 6,
+        description: "add creation time and warn-before-redirect flag to urls",
+        // Links created before this migration keep a NULL created_at, shown as unknown
+        apply: |conn| {
+            conn.execute_batch(
+                "ALTER TABLE urls ADD COLUMN created_at INTEGER;
+                ALTER TABLE urls ADD COLUMN warn_before_redirect INTEGER NOT NULL DEFAULT 0;",
+            )
+        },
+    },
+    Migration {
+        version: 7,
+        description: "add password hash and failed attempt counter to urls",
+        apply: |conn| {
+            conn.execute_batch(
+                "ALTER TABLE urls ADD COLUMN password_hash TEXT;
+                ALTER TABLE urls ADD COLUMN failed_password_attempts INTEGER NOT NULL DEFAULT 0;",
+            )
+        },
+    },
+    Migration {
+        version: 8,
+        description: "add redirect status and query passthrough flag to urls",
+        apply: |conn| {
+            conn.execute_batch(
+                "ALTER TABLE urls ADD COLUMN redirect_status INTEGER NOT NULL DEFAULT 302;
+                ALTER TABLE urls ADD COLUMN forward_query INTEGER NOT NULL DEFAULT 0;",
+            )
+        },
+    },
+    Migration {
+        version: 9,
+        description: "create link_targets table",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE link_targets (
+                    url_id TEXT NOT NULL,
+                    position INTEGER NOT NULL,
+                    url TEXT NOT NULL,
+                    device TEXT,
+                    language TEXT,
+                    weight INTEGER NOT NULL DEFAULT 1,
+                    click_count INTEGER NOT NULL DEFAULT 0,
+                    PRIMARY KEY (url_id, position)
+                );",
+            )
+        },
+    },
+    Migration {
+        version: 10,
+        description: "create link_versions table",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE link_versions (
+                    url_id TEXT NOT NULL,
+                    version INTEGER NOT NULL,
+                    original_url TEXT NOT NULL,
+                    changed_by INTEGER REFERENCES api_keys (id),
+                    changed_at INTEGER,
+                    PRIMARY KEY (url_id, version)
+                );",
+            )
+        },
+    },
+    Migration {
+        version: 11,
+        description: "add title, tags and note to urls with a full-text index",
+        // Tags are stored space-separated. links_fts indexes the urls rows it points at and is kept in
+        // step by triggers, so the code writing urls doesn't need to know about it.
+        apply: |conn| {
+            conn.execute_batch(
+                "ALTER TABLE urls ADD COLUMN title TEXT;
+                ALTER TABLE urls ADD COLUMN tags TEXT;
+                ALTER TABLE urls ADD COLUMN note TEXT;
+                CREATE VIRTUAL TABLE links_fts USING fts5(id, title, tags, original_url, content = 'urls');
+                CREATE TRIGGER urls_fts_insert AFTER INSERT ON urls BEGIN
+                    INSERT INTO links_fts (rowid, id, title, tags, original_url)
+                    VALUES (new.rowid, new.id, new.title, new.tags, new.original_url);
+                END;
+                CREATE TRIGGER urls_fts_delete AFTER DELETE ON urls BEGIN
+                    INSERT INTO links_fts (links_fts, rowid, id, title, tags, original_url)
+                    VALUES ('delete', old.rowid, old.id, old.title, old.tags, old.original_url);
+                END;
+                CREATE TRIGGER urls_fts_update AFTER UPDATE OF id, title, tags, original_url ON urls BEGIN
+                    INSERT INTO links_fts (links_fts, rowid, id, title, tags, original_url)
+                    VALUES ('delete', old.rowid, old.id, old.title, old.tags, old.original_url);
+                    INSERT INTO links_fts (rowid, id, title, tags, original_url)
+                    VALUES (new.rowid, new.id, new.title, new.tags, new.original_url);
+                END;
+                INSERT INTO links_fts (links_fts) VALUES ('rebuild');",
+            )
+        },
+    },
+    Migration {
+        version: 12,
+        description: "create id_sequence for counter-based short IDs",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE id_sequence (
+                    id INTEGER PRIMARY KEY CHECK (id = 1),
+                    value INTEGER NOT NULL
+                );
+                INSERT INTO id_sequence (id, value) VALUES (1, 0);",
+            )
+        },
+    },
+    Migration {
+        version: 13,
+        description: "create instance_settings with a random salt for click IP hashes",
+        // Clicks recorded before this were hashed with a fixed salt, so returning visitors from then are
+        // counted once more as unique
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE TABLE instance_settings (
+                    name TEXT PRIMARY KEY,
+                    value TEXT NOT NULL
+                );
+                INSERT INTO instance_settings (name, value) VALUES ('ip_hash_salt', lower(hex(randomblob(32))));",
+            )
+        },
+    },
+];
+
+// A database without the schema_migrations table is at version 0; reading the version never creates it
+pub fn current_version(conn: &Connection) -> rusqlite::Result<i64> {
+    let tracked: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !tracked {
+        return Ok(0);
+    }
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", [], |row| row.get(0))
+}
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, String> {
+    let current = current_version(conn).map_err(|e| format!("Failed to read schema version: {}", e))?;
+    if current > latest_version() {
+        return Err(format!(
+            "Database schema version {} is newer than the latest version {} known to this build",
+            current,
+            latest_version()
+        ));
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+}
+
+// Returns the migrations that were applied, in order
+pub fn run(conn: &mut Connection) -> Result<Vec<&'static Migration>, String> {
+    let pending = pending(conn)?;
+    if pending.is_empty() {
+        return Ok(pending);
+    }
+
+    let tx = conn.transaction().map_err(|e| format!("Failed to start migration transaction: {}", e))?;
+    ensure_version_table(&tx).map_err(|e| format!("Failed to create schema_migrations table: {}", e))?;
+    for migration in &pending {
+        (migration.apply)(&tx)
+            .and_then(|_| {
+                tx.execute(
+                    "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, ?2, ?3)",
+                    params![migration.version, migration.description, Utc::now().timestamp()],
+                )
+            })
+            .map_err(|e| format!("Migration {} ({}) failed: {}", migration.version, migration.description, e))?;
+    }
+    tx.commit().map_err(|e| format!("Failed to commit migrations: {}", e))?;
+    Ok(pending)
+}
+
+fn ensure_version_table(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );",
+    )
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
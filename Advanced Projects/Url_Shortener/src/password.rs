@@ -0,0 +1,107 @@
+// Password-protected links: the password is stored as an Argon2 hash, visitors get a form instead of a
+// redirect and are only sent on after POSTing the right password back to the short link
+//
+// Wrong guesses are counted per link in failed_password_attempts and slowed down by the redirect rate limit.
+
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::errors::{escape_html, AppError};
+use crate::{click_from, not_found, preview, store, visit_link, AppState, Visit};
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    password: String,
+}
+
+pub fn hash(password: &str) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AppError::Internal(format!("Failed to hash password: {}", e)))
+}
+
+pub fn verify(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+// Stored hashes must be in the PHC string format produced by `hash`
+pub fn validate_hash(hash: &str) -> Result<(), String> {
+    PasswordHash::new(hash).map(|_| ()).map_err(|e| format!("Invalid password hash: {}", e))
+}
+
+// POST /{id} from the password form
+pub async fn unlock(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    form: web::Form<UnlockForm>,
+) -> HttpResponse {
+    let id = id.into_inner();
+    let click = click_from(&req, &data.config);
+    let policy = data.policy.current();
+    let password = form.into_inner().password;
+    let link_id = id.clone();
+
+    let visit = store::run(&data.store, move |store| {
+        let link = store.resolve(&link_id)?.ok_or_else(|| not_found(&link_id))?;
+        if let Some(hash) = &link.password_hash {
+            if !verify(&password, hash) {
+                store.record_failed_password(&link_id)?;
+                return Ok(None);
+            }
+        }
+        visit_link(store, &link_id, &click, &policy, true).map(Some)
+    })
+    .await;
+    // A wrong password is neither a redirect nor a missing link
+    if let Some(outcome) = visit.as_ref().map(Option::as_ref).transpose() {
+        data.metrics.observe_visit(outcome);
+    }
+
+    // The form posts back to the short link with the query string of the original visit
+    let query = req.query_string();
+    match visit {
+        Ok(Some(Visit::Allowed(mut link))) => {
+            link.original_url = link.destination(query);
+            if link.warn_before_redirect {
+                return preview::page(&data.config, &link, true);
+            }
+            // 303 whatever the link's redirect_status, so the browser follows up with a GET instead of
+            // re-POSTing the password
+            HttpResponse::SeeOther().append_header((header::LOCATION, link.original_url)).finish()
+        }
+        Ok(Some(Visit::PasswordRequired)) => form_page(&data.config, &id, query, None),
+        Ok(None) => form_page(&data.config, &id, query, Some("Incorrect password, please try again.")),
+        Err(e) => e.negotiated_response(&req),
+    }
+}
+
+pub fn form_page(config: &Config, id: &str, query: &str, error: Option<&str>) -> HttpResponse {
+    let short_url = escape_html(&config.short_url(id));
+    let action = if query.is_empty() { short_url.clone() } else { format!("{}?{}", short_url, escape_html(query)) };
+    let error_html = error.map(|e| format!("<p><strong>{}</strong></p>\n", escape_html(e))).unwrap_or_default();
+    let body = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><meta name=\"robots\" content=\"noindex\">\
+         <title>Password required</title></head>\n<body>\n<h1>Password required</h1>\n\
+         <p><code>{short_url}</code> is protected. Enter its password to continue.</p>\n{error_html}\
+         <form method=\"post\" action=\"{action}\">\n\
+         <input type=\"password\" name=\"password\" autofocus required>\n\
+         <button type=\"submit\">Continue</button>\n</form>\n</body>\n</html>\n",
+        short_url = short_url,
+        action = action,
+        error_html = error_html,
+    );
+    let status = if error.is_some() { StatusCode::UNAUTHORIZED } else { StatusCode::OK };
+    HttpResponse::build(status)
+        .content_type(ContentType::html())
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .body(body)
+}
@@ -0,0 +1,172 @@
+// Destination policy: which domains and URLs short links may point to
+//
+// The policy lives in its own TOML file (see policy.example.toml), named by the policy_file setting.
+// The file is checked for changes while the server runs, so edits apply without a restart. Destinations
+// are checked when links are created or retargeted, and again on every redirect so links to a newly
+// blocked domain stop working. `cargo run -- policy report` lists the links the current policy refuses.
+
+use regex::Regex;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use url::Url;
+
+use crate::errors::AppError;
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyFile {
+    blocked_domains: Vec<String>,
+    blocked_patterns: Vec<String>,
+    allowed_domains: Vec<String>,
+    allowed_patterns: Vec<String>,
+}
+
+// Domains also match their subdomains; patterns are matched against the whole normalized URL.
+// Blocks win over allows, and once any allow rule exists, everything it doesn't match is refused.
+#[derive(Default)]
+pub struct Policy {
+    blocked_domains: Vec<String>,
+    blocked_patterns: Vec<Regex>,
+    allowed_domains: Vec<String>,
+    allowed_patterns: Vec<Regex>,
+}
+
+#[derive(Serialize)]
+pub struct AffectedLink {
+    pub id: String,
+    // The link's original_url or one of its targets
+    pub destination: String,
+    pub click_count: i64,
+    pub reason: String,
+}
+
+impl Policy {
+    pub fn load(path: &str) -> Result<Policy, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read policy file {}: {}", path, e))?;
+        let file: PolicyFile = toml::from_str(&contents).map_err(|e| format!("Invalid policy file {}: {}", path, e))?;
+        let compile = |patterns: Vec<String>| {
+            patterns
+                .iter()
+                .map(|p| Regex::new(p).map_err(|e| format!("Invalid pattern in policy file {}: {}", path, e)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Policy {
+            blocked_domains: normalize_domains(file.blocked_domains),
+            blocked_patterns: compile(file.blocked_patterns)?,
+            allowed_domains: normalize_domains(file.allowed_domains),
+            allowed_patterns: compile(file.allowed_patterns)?,
+        })
+    }
+
+    // Returns why the destination is refused, or None if it is allowed
+    pub fn check(&self, url: &str) -> Option<String> {
+        let host = Url::parse(url).ok()?.host_str()?.to_ascii_lowercase();
+        if let Some(domain) = self.blocked_domains.iter().find(|d| domain_matches(&host, d)) {
+            return Some(format!("Links to {} are not allowed", domain));
+        }
+        if self.blocked_patterns.iter().any(|p| p.is_match(url)) {
+            return Some(format!("Links to {} are not allowed", url));
+        }
+        let has_allow_list = !self.allowed_domains.is_empty() || !self.allowed_patterns.is_empty();
+        let allow_listed = self.allowed_domains.iter().any(|d| domain_matches(&host, d))
+            || self.allowed_patterns.iter().any(|p| p.is_match(url));
+        if has_allow_list && !allow_listed {
+            return Some(format!("Links to {} are not on the list of allowed destinations", host));
+        }
+        None
+    }
+
+    pub fn enforce(&self, url: &str) -> Result<(), AppError> {
+        match self.check(url) {
+            Some(reason) => Err(AppError::Forbidden(reason)),
+            None => Ok(()),
+        }
+    }
+
+    // Every stored link whose destination the policy refuses
+    pub fn affected_links(&self, conn: &Connection) -> rusqlite::Result<Vec<AffectedLink>> {
+        let mut stmt = conn.prepare("SELECT id, original_url, click_count FROM urls ORDER BY rowid")?;
+        let mut rows = stmt.query([])?;
+        let mut affected = Vec::new();
+        while let Some(row) = rows.next()? {
+            let destination: String = row.get(1)?;
+            if let Some(reason) = self.check(&destination) {
+                affected.push(AffectedLink { id: row.get(0)?, destination, click_count: row.get(2)?, reason });
+            }
+        }
+
+        // Targets are listed under their link's ID with their own click count
+        let mut stmt = conn.prepare(
+            "SELECT link_targets.url_id, link_targets.url, link_targets.click_count
+             FROM link_targets JOIN urls ON urls.id = link_targets.url_id
+             ORDER BY urls.rowid, link_targets.position",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let destination: String = row.get(1)?;
+            if let Some(reason) = self.check(&destination) {
+                affected.push(AffectedLink { id: row.get(0)?, destination, click_count: row.get(2)?, reason });
+            }
+        }
+        Ok(affected)
+    }
+}
+
+// Holds the policy currently in force and swaps in a new one when the file changes
+pub struct PolicyStore {
+    path: Option<String>,
+    current: RwLock<Arc<Policy>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl PolicyStore {
+    // Without a path every destination is allowed
+    pub fn new(path: Option<String>) -> Result<PolicyStore, String> {
+        let (policy, modified) = match &path {
+            Some(path) => (Policy::load(path)?, modified_time(path)),
+            None => (Policy::default(), None),
+        };
+        Ok(PolicyStore { path, current: RwLock::new(Arc::new(policy)), modified: Mutex::new(modified) })
+    }
+
+    pub fn current(&self) -> Arc<Policy> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Returns true when a changed file was loaded. A broken file is reported and the old policy kept.
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+        let mut modified = self.modified.lock().unwrap();
+        let now_modified = modified_time(path);
+        if now_modified == *modified {
+            return Ok(false);
+        }
+        // Remember the new time even when loading fails, so a broken file is reported once, not every check
+        *modified = now_modified;
+        let policy = Policy::load(path)?;
+        *self.current.write().unwrap() = Arc::new(policy);
+        Ok(true)
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain || host.strip_suffix(domain).is_some_and(|rest| rest.ends_with('.'))
+}
+
+fn normalize_domains(domains: Vec<String>) -> Vec<String> {
+    domains
+        .into_iter()
+        .map(|d| d.trim().trim_end_matches('.').to_ascii_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
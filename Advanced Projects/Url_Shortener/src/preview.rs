@@ -0,0 +1,112 @@
+// Preview pages that show where a short link leads instead of redirecting
+//
+// GET /{id}+ always shows the page; links created with warn_before_redirect show it in place of the
+// redirect, as an interstitial with a link to continue.
+
+use actix_web::http::header::{self, ContentType};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+
+use crate::config::Config;
+use crate::errors::escape_html;
+use crate::targeting::Target;
+use crate::{blocked, gone, not_found, password, store, AppState, StoredLink};
+
+// Previewing is not a visit, so unlike a redirect it leaves the click count alone
+pub async fn preview_link(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let id = id.into_inner();
+    let policy = data.policy.current();
+    let link = store::run(&data.store, move |store| {
+        let link = store.resolve(&id)?.ok_or_else(|| not_found(&id))?;
+        if !link.is_usable(Utc::now().timestamp()) {
+            return Err(gone(&id));
+        }
+        if policy.check(&link.original_url).is_some() {
+            return Err(blocked(&id));
+        }
+        Ok(link)
+    })
+    .await;
+
+    match link {
+        // The destination of a protected link is only revealed to those who know the password
+        Ok(link) if link.password_hash.is_some() => password::form_page(&data.config, &link.id, "", None),
+        Ok(link) => page(&data.config, &link, false),
+        Err(e) => e.negotiated_response(&req),
+    }
+}
+
+pub fn page(config: &Config, link: &StoredLink, interstitial: bool) -> HttpResponse {
+    let short_url = escape_html(&config.short_url(&link.id));
+    let destination = escape_html(&link.original_url);
+    let created = link
+        .created_at
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+        .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let (title, intro) = if interstitial {
+        ("You are leaving for another site", "The short link you followed leads to:")
+    } else {
+        ("Short link preview", "This short link leads to:")
+    };
+    // The interstitial already shows the target this visitor was given
+    let targets_html = if interstitial || link.targets.is_empty() {
+        String::new()
+    } else {
+        let items: String = link
+            .targets
+            .iter()
+            .map(|t| format!("<li><code>{}</code>{}</li>\n", escape_html(&t.url), escape_html(&describe(t))))
+            .collect();
+        format!("<p>Depending on the visitor it may instead lead to:</p>\n<ul>\n{}</ul>\n", items)
+    };
+    // Notes are for the owner, only the title is shown to visitors
+    let title_html = match &link.title {
+        Some(link_title) => format!("<li>Title: {}</li>\n", escape_html(link_title)),
+        None => String::new(),
+    };
+
+    let body = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><meta name=\"robots\" content=\"noindex\">\
+         <title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n\
+         <p>{intro}</p>\n<p><code>{destination}</code></p>\n{targets_html}\
+         <ul>\n{title_html}<li>Short link: <code>{short_url}</code></li>\n<li>Created: {created}</li>\n\
+         <li>Clicks: {clicks}</li>\n</ul>\n\
+         <p><a href=\"{destination}\" rel=\"nofollow noopener noreferrer\">Continue to {destination}</a></p>\n\
+         </body>\n</html>\n",
+        title = title,
+        intro = intro,
+        destination = destination,
+        targets_html = targets_html,
+        title_html = title_html,
+        short_url = short_url,
+        created = created,
+        clicks = link.click_count,
+    );
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .append_header((header::CACHE_CONTROL, "no-store"))
+        .body(body)
+}
+
+fn describe(target: &Target) -> String {
+    let mut parts = Vec::new();
+    if let Some(device) = target.when.device {
+        parts.push(format!("{} devices", device.name()));
+    }
+    if let Some(language) = &target.when.language {
+        parts.push(format!("language {}", language));
+    }
+    if target.weight != 1 {
+        parts.push(format!("weight {}", target.weight));
+    }
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!(" ({})", parts.join(", "))
+    }
+}
@@ -0,0 +1,167 @@
+// QR codes for short links, rendered as PNG or SVG
+//
+// GET /{id}/qr?format=svg&size=512&margin=2&ec=H
+//
+// PNGs are drawn with the same whole number of pixels per module, so they can come out a little smaller than
+// `size`; SVGs scale freely and are always `size` wide.
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use qrcode::{Color, EcLevel, QrCode};
+use serde::Deserialize;
+
+use crate::errors::AppError;
+use crate::{not_found, store, AppState};
+
+const DEFAULT_SIZE: u32 = 256;
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 2048;
+// Margin is counted in modules; the QR spec asks for a quiet zone of 4
+const DEFAULT_MARGIN: u32 = 4;
+const MAX_MARGIN: u32 = 16;
+
+#[derive(Deserialize)]
+pub struct QrQuery {
+    format: Option<String>,
+    size: Option<u32>,
+    margin: Option<u32>,
+    ec: Option<String>,
+}
+
+enum Format {
+    Png,
+    Svg,
+}
+
+pub async fn qr_code(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<String>,
+    query: web::Query<QrQuery>,
+) -> Result<HttpResponse, AppError> {
+    let format = match query.format.as_deref() {
+        Some("png") => Format::Png,
+        Some("svg") => Format::Svg,
+        Some(other) => return Err(AppError::Validation(format!("Unknown format '{}', use png or svg", other))),
+        None if accepts_svg(&req) => Format::Svg,
+        None => Format::Png,
+    };
+    let size = query.size.unwrap_or(DEFAULT_SIZE);
+    if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+        return Err(AppError::Validation(format!("size must be between {} and {}", MIN_SIZE, MAX_SIZE)));
+    }
+    let margin = query.margin.unwrap_or(DEFAULT_MARGIN);
+    if margin > MAX_MARGIN {
+        return Err(AppError::Validation(format!("margin must be at most {}", MAX_MARGIN)));
+    }
+    let ec_level = match query.ec.as_deref().map(str::to_ascii_uppercase).as_deref() {
+        Some("L") => EcLevel::L,
+        None | Some("M") => EcLevel::M,
+        Some("Q") => EcLevel::Q,
+        Some("H") => EcLevel::H,
+        Some(other) => return Err(AppError::Validation(format!("Unknown ec level '{}', use L, M, Q or H", other))),
+    };
+
+    let id = id.into_inner();
+    let short_url = data.config.short_url(&id);
+    store::run(&data.store, move |store| store.resolve(&id)?.map(|_| ()).ok_or_else(|| not_found(&id))).await?;
+
+    let code = QrCode::with_error_correction_level(short_url.as_bytes(), ec_level)
+        .map_err(|e| AppError::Validation(format!("Cannot encode link as a QR code: {}", e)))?;
+    let matrix = ModuleMatrix::new(&code, margin);
+
+    let response = match format {
+        Format::Png => {
+            if (size as usize) < matrix.width {
+                return Err(AppError::Validation(format!(
+                    "size must be at least {} for this link at this margin and ec level, one pixel per module",
+                    matrix.width
+                )));
+            }
+            // Large images take a while to encode, so keep that off the async workers
+            let png = web::block(move || matrix.to_png(size)).await??;
+            HttpResponse::Ok().content_type("image/png").body(png)
+        }
+        Format::Svg => HttpResponse::Ok().content_type("image/svg+xml").body(matrix.to_svg(size)),
+    };
+    Ok(response)
+}
+
+fn accepts_svg(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("image/svg+xml"))
+}
+
+// The code's dark/light modules with the quiet zone already added around them
+struct ModuleMatrix {
+    dark: Vec<bool>,
+    width: usize,
+}
+
+impl ModuleMatrix {
+    fn new(code: &QrCode, margin: u32) -> ModuleMatrix {
+        let inner = code.width();
+        let margin = margin as usize;
+        let width = inner + 2 * margin;
+        let colors = code.to_colors();
+
+        let mut dark = vec![false; width * width];
+        for y in 0..inner {
+            for x in 0..inner {
+                dark[(y + margin) * width + x + margin] = colors[y * inner + x] == Color::Dark;
+            }
+        }
+        ModuleMatrix { dark, width }
+    }
+
+    fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.dark[y * self.width + x]
+    }
+
+    fn to_svg(&self, size: u32) -> String {
+        let mut path = String::new();
+        for y in 0..self.width {
+            for x in 0..self.width {
+                if self.is_dark(x, y) {
+                    path.push_str(&format!("M{} {}h1v1h-1z", x, y));
+                }
+            }
+        }
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{size}\" height=\"{size}\" \
+             viewBox=\"0 0 {w} {w}\" shape-rendering=\"crispEdges\">\
+             <rect width=\"{w}\" height=\"{w}\" fill=\"#fff\"/><path d=\"{path}\" fill=\"#000\"/></svg>\n",
+            size = size,
+            w = self.width,
+            path = path,
+        )
+    }
+
+    // Every module becomes the same whole number of pixels, so the image comes out at up to `size` pixels;
+    // uneven modules are harder to scan. `size` must be at least the matrix width.
+    fn to_png(&self, size: u32) -> Result<Vec<u8>, AppError> {
+        let scale = size as usize / self.width;
+        let size_px = scale * self.width;
+        let mut pixels = Vec::with_capacity(size_px * size_px);
+        for py in 0..size_px {
+            let y = py / scale;
+            for px in 0..size_px {
+                let x = px / scale;
+                pixels.push(if self.is_dark(x, y) { 0 } else { 255 });
+            }
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, size_px as u32, size_px as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(|e| AppError::Internal(format!("Failed to encode PNG: {}", e)))?;
+        Ok(png)
+    }
+}
@@ -0,0 +1,263 @@
+// Token-bucket rate limiting as actix middleware
+//
+// Each client gets a bucket holding up to `burst` tokens that refills at `per_minute` tokens a minute;
+// every request takes one token and is answered with 429 when the bucket is empty. Handlers that do more than
+// one request's worth of work, like a batch of links, take the rest with `charge`.
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, ConnectionInfo, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage, HttpRequest, ResponseError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::auth;
+use crate::errors::AppError;
+use crate::store::{self, LinkStore};
+
+// Past this many tracked clients, buckets that have refilled completely are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Link creation, budgeted per API key (or per client IP for anonymous requests) and charged per batch row;
+    // 0 disables the limit
+    pub create_per_minute: u32,
+    pub create_burst: u32,
+    // Redirects, budgeted per client IP; 0 disables the limit
+    pub redirect_per_minute: u32,
+    pub redirect_burst: u32,
+    // Take the client IP from Forwarded/X-Forwarded-For, also for click statistics; only safe behind a proxy
+    // that sets them
+    pub trust_forwarded_headers: bool,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            create_per_minute: 30,
+            create_burst: 10,
+            redirect_per_minute: 600,
+            redirect_burst: 100,
+            trust_forwarded_headers: false,
+        }
+    }
+}
+
+pub enum KeyBy {
+    // Requests with a valid API key share a bucket per key owner; the store is used to check the key
+    ApiKeyOrIp(Arc<dyn LinkStore>),
+    Ip,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+enum Decision {
+    Allowed { remaining: u32, reset_secs: u64 },
+    Limited { retry_after_secs: u64 },
+}
+
+// Left in the request extensions by the middleware, so the handler can charge the same bucket again
+#[derive(Clone)]
+struct Charged {
+    limiter: Arc<RateLimiter>,
+    key: String,
+}
+
+pub struct RateLimiter {
+    per_minute: u32,
+    burst: u32,
+    key_by: KeyBy,
+    trust_forwarded_headers: bool,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, burst: u32, key_by: KeyBy, trust_forwarded_headers: bool) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter {
+            per_minute,
+            burst: burst.max(1),
+            key_by,
+            trust_forwarded_headers,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn enabled(&self) -> bool {
+        self.per_minute > 0
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+
+    async fn client_key(&self, req: &ServiceRequest) -> String {
+        if let KeyBy::ApiKeyOrIp(store) = &self.key_by {
+            // Keying on the token as sent would give every made-up token a full bucket of its own, so tokens
+            // that don't resolve to an active key fall back to the client's IP bucket
+            if let Some(token) = auth::bearer_token(req.request()) {
+                if let Ok(Some(owner)) = store::run(store, move |store| store.owner_for_key(&token)).await {
+                    return format!("key:{}", owner);
+                }
+            }
+        }
+
+        format!("ip:{}", client_ip(&req.connection_info(), self.trust_forwarded_headers).unwrap_or("unknown"))
+    }
+
+    // Takes `tokens` from the bucket of `key`, or nothing at all when it holds fewer
+    fn check(&self, key: &str, tokens: u32) -> Decision {
+        let now = Instant::now();
+        let capacity = f64::from(self.burst);
+        let rate = self.refill_per_sec();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+
+        let tokens = f64::from(tokens);
+        if bucket.tokens >= tokens {
+            bucket.tokens -= tokens;
+            Decision::Allowed {
+                remaining: bucket.tokens.floor() as u32,
+                reset_secs: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            }
+        } else {
+            Decision::Limited { retry_after_secs: ((tokens - bucket.tokens) / rate).ceil() as u64 }
+        }
+    }
+
+    // What is left in the bucket of `key` without taking anything, for the response headers
+    fn remaining(&self, key: &str) -> (u32, u64) {
+        match self.check(key, 0) {
+            Decision::Allowed { remaining, reset_secs } => (remaining, reset_secs),
+            Decision::Limited { .. } => (0, 0),
+        }
+    }
+}
+
+// Raises the price of a request admitted by the middleware to `cost` tokens, taking the ones it didn't pay
+// yet; a no-op when no limit applies. A cost above the burst always fails, the bucket never holds that many.
+pub fn charge(req: &HttpRequest, cost: u32) -> Result<(), AppError> {
+    let charged = match req.extensions().get::<Charged>() {
+        Some(charged) => charged.clone(),
+        None => return Ok(()),
+    };
+    let burst = charged.limiter.burst;
+    if cost > burst {
+        return Err(AppError::RateLimited(format!("This request costs {} tokens, more than the burst of {}", cost, burst)));
+    }
+    match charged.limiter.check(&charged.key, cost.saturating_sub(1)) {
+        Decision::Allowed { .. } => Ok(()),
+        Decision::Limited { retry_after_secs } => Err(AppError::RateLimited(format!(
+            "Rate limit exceeded, this request costs {} tokens, retry in {} seconds",
+            cost, retry_after_secs
+        ))),
+    }
+}
+
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>) -> RateLimit {
+        RateLimit { limiter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware { service: Rc::new(service), limiter: self.limiter.clone() }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            if !limiter.enabled() {
+                return service.call(req).await.map(ServiceResponse::map_into_left_body);
+            }
+
+            let limit = limiter.burst;
+            let key = limiter.client_key(&req).await;
+            match limiter.check(&key, 1) {
+                Decision::Allowed { .. } => {
+                    req.extensions_mut().insert(Charged { limiter: limiter.clone(), key: key.clone() });
+                    let mut res = service.call(req).await?;
+                    // Read after the handler, which may have charged more
+                    let (remaining, reset_secs) = limiter.remaining(&key);
+                    set_limit_headers(res.headers_mut(), limit, remaining, reset_secs);
+                    Ok(res.map_into_left_body())
+                }
+                Decision::Limited { retry_after_secs } => {
+                    let mut response =
+                        AppError::RateLimited(format!("Rate limit exceeded, retry in {} seconds", retry_after_secs))
+                            .error_response();
+                    let headers = response.headers_mut();
+                    set_limit_headers(headers, limit, 0, retry_after_secs);
+                    headers.insert(header::RETRY_AFTER, HeaderValue::from(retry_after_secs));
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+// The address of the client; forwarded headers are only believed when the server sits behind a proxy that sets
+// them, anyone can send their own otherwise
+pub fn client_ip(info: &ConnectionInfo, trust_forwarded_headers: bool) -> Option<&str> {
+    if trust_forwarded_headers {
+        info.realip_remote_addr()
+    } else {
+        info.peer_addr()
+    }
+}
+
+fn set_limit_headers(headers: &mut header::HeaderMap, limit: u32, remaining: u32, reset_secs: u64) {
+    headers.insert(HeaderName::from_static("x-ratelimit-limit"), HeaderValue::from(limit));
+    headers.insert(HeaderName::from_static("x-ratelimit-remaining"), HeaderValue::from(remaining));
+    headers.insert(HeaderName::from_static("x-ratelimit-reset"), HeaderValue::from(reset_secs));
+}
@@ -0,0 +1,205 @@
+// Storage backends for links, selected with the `storage` setting
+//
+// Handlers only talk to a `LinkStore`, so they work the same on the SQLite database and on the in-memory
+// store. The admin subcommands (keys, export, import, policy report) work on the database file directly.
+
+pub mod cache;
+pub mod memory;
+pub mod sqlite;
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use url::{form_urlencoded, Url};
+
+use crate::analytics::{Click, LinkStats};
+use crate::errors::AppError;
+use crate::targeting::Target;
+use crate::NewLink;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Sqlite,
+    // Nothing survives a restart and API keys can't be created, so every link is anonymous
+    Memory,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "sqlite" => Some(Backend::Sqlite),
+            "memory" => Some(Backend::Memory),
+            _ => None,
+        }
+    }
+}
+
+pub trait LinkStore: Send + Sync {
+    // Stores the link under its alias or a generated ID and returns the ID. Unrestricted links without an
+    // alias reuse an identical existing link of the same owner, which `Created::inserted` tells apart.
+    fn create(&self, link: &NewLink) -> Result<Created, AppError>;
+
+    // Creates every link or, unless `partial` is set, none of them if any fails
+    fn create_many(&self, links: Vec<Result<NewLink, AppError>>, partial: bool) -> Result<BatchResult, AppError>;
+
+    // Returns the link even when it has expired or used up its clicks; see `StoredLink::is_usable`
+    fn resolve(&self, id: &str) -> Result<Option<StoredLink>, AppError>;
+
+    // Counts a visit, but only while the link is still usable; returns false when it wasn't counted
+    // `target` is the index of the target the visit went to, None for original_url
+    fn record_hit(&self, id: &str, click: &Click, target: Option<usize>) -> Result<bool, AppError>;
+
+    fn record_failed_password(&self, id: &str) -> Result<(), AppError>;
+
+    // One page of the links owned by an API key that match the query, oldest first
+    fn list(&self, owner_id: i64, query: &LinkQuery) -> Result<LinkPage, AppError>;
+
+    // Returns None when the link doesn't exist. A new original_url is recorded as the next version, set
+    // by the API key `changed_by`.
+    fn update(&self, id: &str, update: &LinkUpdate, changed_by: i64) -> Result<Option<StoredLink>, AppError>;
+
+    // Every destination the link has had, oldest first; the last one is current
+    fn versions(&self, id: &str) -> Result<Option<Vec<LinkVersion>>, AppError>;
+
+    // Removes the link and its clicks; returns false when it didn't exist
+    fn delete(&self, id: &str) -> Result<bool, AppError>;
+
+    fn stats(&self, id: &str) -> Result<Option<LinkStats>, AppError>;
+
+    // The owner ID of an active API key
+    fn owner_for_key(&self, key: &str) -> Result<Option<i64>, AppError>;
+
+    // Deletes links past their expiry date and returns how many were removed
+    fn sweep_expired(&self) -> Result<usize, AppError>;
+}
+
+#[derive(Clone)]
+pub struct StoredLink {
+    pub id: String,
+    pub original_url: String,
+    pub owner_id: Option<i64>,
+    pub created_at: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub max_clicks: Option<i64>,
+    pub click_count: i64,
+    pub warn_before_redirect: bool,
+    pub password_hash: Option<String>,
+    pub failed_password_attempts: i64,
+    pub redirect_status: u16,
+    pub forward_query: bool,
+    pub targets: Vec<Target>,
+    pub title: Option<String>,
+    pub tags: Vec<String>,
+    pub note: Option<String>,
+}
+
+impl StoredLink {
+    // Whether the link can still be followed at `now` (unix seconds)
+    pub fn is_usable(&self, now: i64) -> bool {
+        self.expires_at.is_none_or(|t| t > now) && self.max_clicks.is_none_or(|max| self.click_count < max)
+    }
+
+    // Where a visit with the given query string is sent. With forward_query the visit's parameters are
+    // appended to original_url, except those it already sets, so visitors can't override them.
+    pub fn destination(&self, query: &str) -> String {
+        if !self.forward_query || query.is_empty() {
+            return self.original_url.clone();
+        }
+        let mut url = match Url::parse(&self.original_url) {
+            Ok(url) => url,
+            Err(_) => return self.original_url.clone(),
+        };
+        let existing: HashSet<String> = url.query_pairs().map(|(name, _)| name.into_owned()).collect();
+        let forwarded: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .filter(|(name, _)| !existing.contains(name))
+            .collect();
+        if forwarded.is_empty() {
+            return self.original_url.clone();
+        }
+        url.query_pairs_mut().extend_pairs(forwarded);
+        url.into()
+    }
+}
+
+// Fields left as None keep their current value
+pub struct LinkUpdate {
+    pub original_url: Option<String>,
+    pub warn_before_redirect: Option<bool>,
+    pub redirect_status: Option<u16>,
+    pub forward_query: Option<bool>,
+    // Replaces every target, starting their click counts over
+    pub targets: Option<Vec<Target>>,
+    // Some("") clears the title or note
+    pub title: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub note: Option<String>,
+}
+
+// Filters for `LinkStore::list`; fields left as None match every link
+pub struct LinkQuery {
+    // Words that must all appear in the short ID, title, tags or destination, matched as word prefixes
+    pub text: Option<String>,
+    pub tag: Option<String>,
+    pub created_after: Option<i64>,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+pub struct LinkPage {
+    pub links: Vec<StoredLink>,
+    // Matching links across all pages
+    pub total: usize,
+}
+
+#[derive(Clone, Serialize)]
+pub struct LinkVersion {
+    pub version: i64,
+    pub original_url: String,
+    // The API key that set this destination; None when the link was created anonymously
+    pub changed_by: Option<i64>,
+    // None for the first version of links created before creation times were kept
+    pub changed_at: Option<i64>,
+}
+
+impl LinkVersion {
+    // Links that were never retargeted have no stored history, only the destination they were created with
+    fn first(link: &StoredLink) -> LinkVersion {
+        LinkVersion {
+            version: 1,
+            original_url: link.original_url.clone(),
+            changed_by: link.owner_id,
+            changed_at: link.created_at,
+        }
+    }
+}
+
+pub struct Created {
+    pub id: String,
+    // False when an existing link was handed back instead
+    pub inserted: bool,
+}
+
+pub struct BatchResult {
+    // The created link or the reason each link was refused, in input order
+    pub outcomes: Vec<Result<Created, AppError>>,
+    pub committed: bool,
+}
+
+// Only problems with the link itself are reported per row; anything else fails the whole batch
+fn is_row_error(err: &AppError) -> bool {
+    !matches!(err, AppError::DatabaseBusy(_) | AppError::Database(_) | AppError::Internal(_))
+}
+
+// Runs `f` on actix's blocking thread pool, so storage work never stalls an async worker
+pub async fn run<F, T>(store: &Arc<dyn LinkStore>, f: F) -> Result<T, AppError>
+where
+    F: FnOnce(&dyn LinkStore) -> Result<T, AppError> + Send + 'static,
+    T: Send + 'static,
+{
+    let store = store.clone();
+    web::block(move || f(store.as_ref())).await?
+}
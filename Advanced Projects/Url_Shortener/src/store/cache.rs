@@ -0,0 +1,284 @@
+// An LRU cache in front of another link store, so popular links are resolved without a database round trip
+//
+// Only links that exist are cached, for at most `ttl_seconds`. Writes made through the store drop or
+// patch the cached copy; the TTL bounds how long changes made outside the server (imports) go unseen.
+//
+// A miss reads the inner store without holding the cache lock, so a write can land in between. Every write
+// bumps the generation of the ID, and a miss only caches what it read if the generation is unchanged.
+
+use lru::LruCache;
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{BatchResult, Created, LinkPage, LinkQuery, LinkStore, LinkUpdate, LinkVersion, StoredLink};
+use crate::analytics::{Click, LinkStats};
+use crate::errors::AppError;
+use crate::NewLink;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // Most links kept in memory; 0 disables the cache
+    pub capacity: usize,
+    pub ttl_seconds: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { capacity: 10_000, ttl_seconds: 60 }
+    }
+}
+
+pub struct CachedStore {
+    inner: Arc<dyn LinkStore>,
+    ttl: Duration,
+    entries: Mutex<LruCache<String, CachedLink>>,
+    // Generations of the IDs with a miss in progress; locked before `entries` when both are needed
+    generations: Mutex<HashMap<String, Generation>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct CachedLink {
+    cached_at: Instant,
+    link: StoredLink,
+}
+
+#[derive(Default)]
+struct Generation {
+    value: u64,
+    // Misses still reading; the ID is forgotten when the last one finishes
+    readers: usize,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CachedStore {
+    // Returns None when the configured capacity is 0
+    pub fn new(inner: Arc<dyn LinkStore>, config: &CacheConfig) -> Option<CachedStore> {
+        let capacity = NonZeroUsize::new(config.capacity)?;
+        Some(CachedStore {
+            inner,
+            ttl: Duration::from_secs(config.ttl_seconds),
+            entries: Mutex::new(LruCache::new(capacity)),
+            generations: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            capacity: entries.cap().get(),
+            entries: entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn cached(&self, id: &str) -> Option<StoredLink> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(id) {
+            Some(entry) if entry.cached_at.elapsed() < self.ttl => Some(entry.link.clone()),
+            Some(_) => {
+                entries.pop(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn invalidate(&self, id: &str) {
+        let mut generations = self.generations.lock().unwrap();
+        if let Some(generation) = generations.get_mut(id) {
+            generation.value += 1;
+        }
+        self.entries.lock().unwrap().pop(id);
+    }
+
+    fn invalidate_all(&self) {
+        let mut generations = self.generations.lock().unwrap();
+        for generation in generations.values_mut() {
+            generation.value += 1;
+        }
+        self.entries.lock().unwrap().clear();
+    }
+
+    // Registers a miss for `id` and returns the generation it started at
+    fn start_miss(&self, id: &str) -> u64 {
+        let mut generations = self.generations.lock().unwrap();
+        let generation = generations.entry(id.to_string()).or_default();
+        generation.readers += 1;
+        generation.value
+    }
+
+    // Caches what a miss read unless the link was written since `started_at`
+    fn finish_miss(&self, id: &str, started_at: u64, link: Option<&StoredLink>) {
+        let mut generations = self.generations.lock().unwrap();
+        let current = match generations.get_mut(id) {
+            Some(generation) => {
+                generation.readers -= 1;
+                generation.value == started_at
+            }
+            None => false,
+        };
+        if matches!(generations.get(id), Some(generation) if generation.readers == 0) {
+            generations.remove(id);
+        }
+        if let Some(link) = link.filter(|_| current) {
+            let entry = CachedLink { cached_at: Instant::now(), link: link.clone() };
+            self.entries.lock().unwrap().put(id.to_string(), entry);
+        }
+    }
+}
+
+impl LinkStore for CachedStore {
+    fn create(&self, link: &NewLink) -> Result<Created, AppError> {
+        self.inner.create(link)
+    }
+
+    fn create_many(&self, links: Vec<Result<NewLink, AppError>>, partial: bool) -> Result<BatchResult, AppError> {
+        self.inner.create_many(links, partial)
+    }
+
+    fn resolve(&self, id: &str) -> Result<Option<StoredLink>, AppError> {
+        if let Some(link) = self.cached(id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(link));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let started_at = self.start_miss(id);
+        let link = self.inner.resolve(id);
+        self.finish_miss(id, started_at, link.as_ref().ok().and_then(Option::as_ref));
+        link
+    }
+
+    fn record_hit(&self, id: &str, click: &Click, target: Option<usize>) -> Result<bool, AppError> {
+        let counted = self.inner.record_hit(id, click, target)?;
+        if !counted {
+            self.invalidate(id);
+            return Ok(false);
+        }
+        let mut generations = self.generations.lock().unwrap();
+        // A miss in flight may have read the count from before this hit
+        if let Some(generation) = generations.get_mut(id) {
+            generation.value += 1;
+        }
+        // Keep the cached click counts in step, so max_clicks is checked against the real number
+        if let Some(entry) = self.entries.lock().unwrap().peek_mut(id) {
+            entry.link.click_count += 1;
+            if let Some(target) = target.and_then(|i| entry.link.targets.get_mut(i)) {
+                target.click_count += 1;
+            }
+        }
+        Ok(true)
+    }
+
+    fn record_failed_password(&self, id: &str) -> Result<(), AppError> {
+        self.inner.record_failed_password(id)?;
+        self.invalidate(id);
+        Ok(())
+    }
+
+    fn list(&self, owner_id: i64, query: &LinkQuery) -> Result<LinkPage, AppError> {
+        self.inner.list(owner_id, query)
+    }
+
+    fn update(&self, id: &str, update: &LinkUpdate, changed_by: i64) -> Result<Option<StoredLink>, AppError> {
+        let link = self.inner.update(id, update, changed_by)?;
+        self.invalidate(id);
+        Ok(link)
+    }
+
+    fn versions(&self, id: &str) -> Result<Option<Vec<LinkVersion>>, AppError> {
+        self.inner.versions(id)
+    }
+
+    fn delete(&self, id: &str) -> Result<bool, AppError> {
+        let deleted = self.inner.delete(id)?;
+        self.invalidate(id);
+        Ok(deleted)
+    }
+
+    fn stats(&self, id: &str) -> Result<Option<LinkStats>, AppError> {
+        self.inner.stats(id)
+    }
+
+    fn owner_for_key(&self, key: &str) -> Result<Option<i64>, AppError> {
+        self.inner.owner_for_key(key)
+    }
+
+    fn sweep_expired(&self) -> Result<usize, AppError> {
+        let removed = self.inner.sweep_expired()?;
+        if removed > 0 {
+            self.invalidate_all();
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::store::memory::MemoryStore;
+
+    fn store_with_link(id: &str) -> (Arc<MemoryStore>, CachedStore) {
+        let inner = Arc::new(MemoryStore::new(Config::default()));
+        let link = NewLink {
+            original_url: "https://example.com/".to_string(),
+            alias: Some(id.to_string()),
+            owner_id: None,
+            expires_at: None,
+            max_clicks: None,
+            warn_before_redirect: false,
+            password_hash: None,
+            redirect_status: 302,
+            forward_query: false,
+            targets: Vec::new(),
+            title: None,
+            tags: Vec::new(),
+            note: None,
+        };
+        inner.create(&link).unwrap();
+        let cache = CachedStore::new(inner.clone(), &CacheConfig::default()).unwrap();
+        (inner, cache)
+    }
+
+    #[test]
+    fn test_miss_is_cached() {
+        let (inner, cache) = store_with_link("docs");
+        let started_at = cache.start_miss("docs");
+        let link = inner.resolve("docs").unwrap();
+        cache.finish_miss("docs", started_at, link.as_ref());
+
+        assert!(cache.resolve("docs").unwrap().is_some());
+        assert_eq!(cache.stats().hits, 1);
+        assert!(cache.generations.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_miss_overtaken_by_write_is_not_cached() {
+        let (inner, cache) = store_with_link("docs");
+        let started_at = cache.start_miss("docs");
+        let stale = inner.resolve("docs").unwrap();
+        // The delete lands between the miss reading the link and caching it
+        assert!(cache.delete("docs").unwrap());
+        cache.finish_miss("docs", started_at, stale.as_ref());
+
+        assert!(cache.resolve("docs").unwrap().is_none());
+        assert!(cache.generations.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,356 @@
+// A link store that keeps everything in process memory, for tests and throwaway instances
+//
+// API keys normally live in the database, so a memory store only knows the keys tests add with `add_api_key`.
+
+use chrono::{DateTime, Utc};
+use nanoid::nanoid;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::{
+    is_row_error, BatchResult, Created, LinkPage, LinkQuery, LinkStore, LinkUpdate, LinkVersion, StoredLink,
+};
+use crate::analytics::{self, Click, DailyClicks, LinkStats, ReferrerClicks, TargetClicks};
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::ids::IdGenerator;
+use crate::NewLink;
+
+pub struct MemoryStore {
+    config: Config,
+    // Fresh for every process, like everything else kept here
+    ip_salt: String,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    links: HashMap<String, Entry>,
+    clicks: HashMap<String, Vec<MemoryClick>>,
+    // Only links that were retargeted have a history, as in the SQLite store
+    versions: HashMap<String, Vec<LinkVersion>>,
+    // Insertion counter, so listings come out oldest first like the SQLite rowid order
+    next_seq: u64,
+    // Last number handed out to the counter ID strategy
+    id_sequence: u64,
+    // Owner IDs by plaintext key
+    api_keys: HashMap<String, i64>,
+}
+
+struct Entry {
+    seq: u64,
+    link: StoredLink,
+}
+
+struct MemoryClick {
+    clicked_at: i64,
+    referrer: Option<String>,
+    ip_hash: Option<String>,
+}
+
+impl MemoryStore {
+    pub fn new(config: Config) -> MemoryStore {
+        MemoryStore { config, ip_salt: nanoid!(32), state: Mutex::new(MemoryState::default()) }
+    }
+
+    #[cfg(test)]
+    pub fn add_api_key(&self, key: &str, owner_id: i64) {
+        self.state.lock().unwrap().api_keys.insert(key.to_string(), owner_id);
+    }
+}
+
+impl MemoryState {
+    fn insert(&mut self, id: &str, link: &NewLink) {
+        let stored = StoredLink {
+            id: id.to_string(),
+            original_url: link.original_url.clone(),
+            owner_id: link.owner_id,
+            created_at: Some(Utc::now().timestamp()),
+            expires_at: link.expires_at,
+            max_clicks: link.max_clicks,
+            click_count: 0,
+            warn_before_redirect: link.warn_before_redirect,
+            password_hash: link.password_hash.clone(),
+            failed_password_attempts: 0,
+            redirect_status: link.redirect_status,
+            forward_query: link.forward_query,
+            targets: link.targets.clone(),
+            title: link.title.clone(),
+            tags: link.tags.clone(),
+            note: link.note.clone(),
+        };
+        self.next_seq += 1;
+        self.links.insert(id.to_string(), Entry { seq: self.next_seq, link: stored });
+    }
+
+    fn create(&mut self, config: &Config, link: &NewLink) -> Result<Created, AppError> {
+        if let Some(alias) = &link.alias {
+            if self.links.contains_key(alias) {
+                return Err(AppError::Conflict(format!("Alias '{}' is already in use", alias)));
+            }
+            self.insert(alias, link);
+            return Ok(Created { id: alias.clone(), inserted: true });
+        }
+
+        // Same reuse rule as the SQLite store: an identical unrestricted link of the same owner
+        if link.is_reusable() {
+            let existing = self
+                .links
+                .values()
+                .filter(|e| {
+                    let l = &e.link;
+                    l.original_url == link.original_url
+                        && l.owner_id == link.owner_id
+                        && l.expires_at.is_none()
+                        && l.max_clicks.is_none()
+                        && l.warn_before_redirect == link.warn_before_redirect
+                        && l.password_hash.is_none()
+                        && l.redirect_status == link.redirect_status
+                        && l.forward_query == link.forward_query
+                        && l.targets.is_empty()
+                        && l.title.is_none()
+                        && l.tags.is_empty()
+                        && l.note.is_none()
+                })
+                .min_by_key(|e| e.seq);
+            if let Some(entry) = existing {
+                return Ok(Created { id: entry.link.id.clone(), inserted: false });
+            }
+        }
+
+        let links = &self.links;
+        let sequence = &mut self.id_sequence;
+        let id = IdGenerator::new(config).generate(
+            &link.original_url,
+            || {
+                *sequence += 1;
+                Ok(*sequence)
+            },
+            |id| Ok(!links.contains_key(id)),
+        )?;
+        self.insert(&id, link);
+        Ok(Created { id, inserted: true })
+    }
+
+    fn remove(&mut self, id: &str) -> bool {
+        self.clicks.remove(id);
+        self.versions.remove(id);
+        self.links.remove(id).is_some()
+    }
+}
+
+impl LinkStore for MemoryStore {
+    fn create(&self, link: &NewLink) -> Result<Created, AppError> {
+        self.state.lock().unwrap().create(&self.config, link)
+    }
+
+    fn create_many(&self, links: Vec<Result<NewLink, AppError>>, partial: bool) -> Result<BatchResult, AppError> {
+        let mut state = self.state.lock().unwrap();
+        // IDs inserted by this batch, so a rollback can take them out again
+        let mut inserted = Vec::new();
+        let mut outcomes = Vec::with_capacity(links.len());
+        for link in links {
+            match link.and_then(|link| state.create(&self.config, &link)) {
+                Err(e) if !is_row_error(&e) => return Err(e),
+                outcome => {
+                    match &outcome {
+                        Ok(created) if created.inserted => inserted.push(created.id.clone()),
+                        _ => {}
+                    }
+                    outcomes.push(outcome);
+                }
+            }
+        }
+
+        let committed = partial || outcomes.iter().all(Result::is_ok);
+        if !committed {
+            for id in &inserted {
+                state.remove(id);
+            }
+        }
+        Ok(BatchResult { outcomes, committed })
+    }
+
+    fn resolve(&self, id: &str) -> Result<Option<StoredLink>, AppError> {
+        Ok(self.state.lock().unwrap().links.get(id).map(|e| e.link.clone()))
+    }
+
+    fn record_hit(&self, id: &str, click: &Click, target: Option<usize>) -> Result<bool, AppError> {
+        let now = Utc::now().timestamp();
+        let mut state = self.state.lock().unwrap();
+        match state.links.get_mut(id) {
+            Some(entry) if entry.link.is_usable(now) => {
+                entry.link.click_count += 1;
+                if let Some(target) = target.and_then(|i| entry.link.targets.get_mut(i)) {
+                    target.click_count += 1;
+                }
+            }
+            _ => return Ok(false),
+        }
+        state.clicks.entry(id.to_string()).or_default().push(MemoryClick {
+            clicked_at: now,
+            referrer: click.referrer.clone(),
+            ip_hash: click.client_ip.as_deref().map(|ip| analytics::hash_ip(ip, &self.ip_salt)),
+        });
+        Ok(true)
+    }
+
+    fn record_failed_password(&self, id: &str) -> Result<(), AppError> {
+        if let Some(entry) = self.state.lock().unwrap().links.get_mut(id) {
+            entry.link.failed_password_attempts += 1;
+        }
+        Ok(())
+    }
+
+    fn list(&self, owner_id: i64, query: &LinkQuery) -> Result<LinkPage, AppError> {
+        let state = self.state.lock().unwrap();
+        // Substring matches stand in for the SQLite store's full-text prefix matches
+        let words: Vec<String> = query
+            .text
+            .as_deref()
+            .unwrap_or("")
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect();
+        let tag = query.tag.as_deref().map(str::to_lowercase);
+        let mut entries: Vec<&Entry> = state
+            .links
+            .values()
+            .filter(|e| {
+                let l = &e.link;
+                let searchable =
+                    format!("{} {} {} {}", l.id, l.title.as_deref().unwrap_or(""), l.tags.join(" "), l.original_url)
+                        .to_lowercase();
+                l.owner_id == Some(owner_id)
+                    && words.iter().all(|word| searchable.contains(word.as_str()))
+                    && tag.as_ref().is_none_or(|tag| l.tags.contains(tag))
+                    && query.created_after.is_none_or(|after| l.created_at.is_some_and(|t| t > after))
+            })
+            .collect();
+        entries.sort_by_key(|e| e.seq);
+        let total = entries.len();
+        let links = entries.into_iter().skip(query.offset).take(query.limit).map(|e| e.link.clone()).collect();
+        Ok(LinkPage { links, total })
+    }
+
+    fn update(&self, id: &str, update: &LinkUpdate, changed_by: i64) -> Result<Option<StoredLink>, AppError> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let entry = match state.links.get_mut(id) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if let Some(url) = update.original_url.as_ref().filter(|url| **url != entry.link.original_url) {
+            let history = state.versions.entry(id.to_string()).or_default();
+            if history.is_empty() {
+                history.push(LinkVersion::first(&entry.link));
+            }
+            history.push(LinkVersion {
+                version: history.len() as i64 + 1,
+                original_url: url.clone(),
+                changed_by: Some(changed_by),
+                changed_at: Some(Utc::now().timestamp()),
+            });
+            entry.link.original_url = url.clone();
+        }
+        if let Some(warn) = update.warn_before_redirect {
+            entry.link.warn_before_redirect = warn;
+        }
+        if let Some(status) = update.redirect_status {
+            entry.link.redirect_status = status;
+        }
+        if let Some(forward) = update.forward_query {
+            entry.link.forward_query = forward;
+        }
+        if let Some(targets) = &update.targets {
+            entry.link.targets = targets.clone();
+        }
+        if let Some(title) = &update.title {
+            entry.link.title = (!title.is_empty()).then(|| title.clone());
+        }
+        if let Some(tags) = &update.tags {
+            entry.link.tags = tags.clone();
+        }
+        if let Some(note) = &update.note {
+            entry.link.note = (!note.is_empty()).then(|| note.clone());
+        }
+        Ok(Some(entry.link.clone()))
+    }
+
+    fn versions(&self, id: &str) -> Result<Option<Vec<LinkVersion>>, AppError> {
+        let state = self.state.lock().unwrap();
+        let entry = match state.links.get(id) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        match state.versions.get(id) {
+            Some(history) => Ok(Some(history.clone())),
+            None => Ok(Some(vec![LinkVersion::first(&entry.link)])),
+        }
+    }
+
+    fn delete(&self, id: &str) -> Result<bool, AppError> {
+        Ok(self.state.lock().unwrap().remove(id))
+    }
+
+    fn stats(&self, id: &str) -> Result<Option<LinkStats>, AppError> {
+        let state = self.state.lock().unwrap();
+        if !state.links.contains_key(id) {
+            return Ok(None);
+        }
+        let clicks = state.clicks.get(id).map(Vec::as_slice).unwrap_or_default();
+        let targets = state.links[id]
+            .link
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(position, t)| TargetClicks { position, clicks: t.click_count })
+            .collect();
+
+        let unique_visitors = clicks.iter().filter_map(|c| c.ip_hash.as_deref()).collect::<HashSet<_>>().len();
+        let mut per_day: BTreeMap<String, i64> = BTreeMap::new();
+        let mut per_referrer: HashMap<&str, i64> = HashMap::new();
+        for click in clicks {
+            let day = DateTime::from_timestamp(click.clicked_at, 0).unwrap_or_default().format("%Y-%m-%d");
+            *per_day.entry(day.to_string()).or_default() += 1;
+            if let Some(referrer) = &click.referrer {
+                *per_referrer.entry(referrer).or_default() += 1;
+            }
+        }
+        let mut top_referrers: Vec<ReferrerClicks> = per_referrer
+            .into_iter()
+            .map(|(referrer, clicks)| ReferrerClicks { referrer: referrer.to_string(), clicks })
+            .collect();
+        top_referrers.sort_by(|a, b| b.clicks.cmp(&a.clicks).then_with(|| a.referrer.cmp(&b.referrer)));
+        top_referrers.truncate(analytics::TOP_REFERRERS);
+
+        Ok(Some(LinkStats {
+            id: id.to_string(),
+            total_clicks: clicks.len() as i64,
+            unique_visitors: unique_visitors as i64,
+            clicks_per_day: per_day.into_iter().map(|(date, clicks)| DailyClicks { date, clicks }).collect(),
+            top_referrers,
+            targets,
+        }))
+    }
+
+    fn owner_for_key(&self, key: &str) -> Result<Option<i64>, AppError> {
+        Ok(self.state.lock().unwrap().api_keys.get(key).copied())
+    }
+
+    fn sweep_expired(&self) -> Result<usize, AppError> {
+        let now = Utc::now().timestamp();
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<String> = state
+            .links
+            .values()
+            .filter(|e| e.link.expires_at.is_some_and(|t| t <= now))
+            .map(|e| e.link.id.clone())
+            .collect();
+        for id in &expired {
+            state.remove(id);
+        }
+        Ok(expired.len())
+    }
+}
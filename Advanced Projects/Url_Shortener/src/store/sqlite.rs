@@ -0,0 +1,467 @@
+// The SQLite link store, backed by the pooled connections from `db`
+
+use chrono::Utc;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, ErrorCode, OptionalExtension, Row};
+
+use super::{
+    is_row_error, BatchResult, Created, LinkPage, LinkQuery, LinkStore, LinkUpdate, LinkVersion, StoredLink,
+};
+use crate::analytics::{self, Click, LinkStats};
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::ids::IdGenerator;
+use crate::targeting::{Condition, Device, Target};
+use crate::{auth, db, NewLink};
+
+// Columns read by `read_link`, in order
+const LINK_COLUMNS: &str = "id, original_url, owner_id, created_at, expires_at, max_clicks, click_count, \
+                            warn_before_redirect, password_hash, failed_password_attempts, redirect_status, \
+                            forward_query, title, tags, note";
+
+pub struct SqliteStore {
+    pool: db::Pool,
+    config: Config,
+    // See analytics::load_ip_salt
+    ip_salt: String,
+}
+
+impl SqliteStore {
+    pub fn new(pool: db::Pool, config: Config, ip_salt: String) -> SqliteStore {
+        SqliteStore { pool, config, ip_salt }
+    }
+}
+
+impl LinkStore for SqliteStore {
+    fn create(&self, link: &NewLink) -> Result<Created, AppError> {
+        let conn = self.pool.get()?;
+        create_link(&conn, &self.config, link)
+    }
+
+    fn create_many(&self, links: Vec<Result<NewLink, AppError>>, partial: bool) -> Result<BatchResult, AppError> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let mut outcomes = Vec::with_capacity(links.len());
+        for link in links {
+            match link.and_then(|link| create_link(&tx, &self.config, &link)) {
+                Err(e) if !is_row_error(&e) => return Err(e),
+                outcome => outcomes.push(outcome),
+            }
+        }
+
+        let committed = partial || outcomes.iter().all(Result::is_ok);
+        if committed {
+            tx.commit()?;
+        }
+        Ok(BatchResult { outcomes, committed })
+    }
+
+    fn resolve(&self, id: &str) -> Result<Option<StoredLink>, AppError> {
+        let conn = self.pool.get()?;
+        let link = conn
+            .query_row(&format!("SELECT {} FROM urls WHERE id = ?1", LINK_COLUMNS), params![id], read_link)
+            .optional()?;
+        Ok(link.map(|link| with_targets(&conn, link)).transpose()?)
+    }
+
+    fn record_hit(&self, id: &str, click: &Click, target: Option<usize>) -> Result<bool, AppError> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        // Checking the limits in the UPDATE itself keeps max_clicks exact under concurrency
+        let counted = tx.execute(
+            "UPDATE urls SET click_count = click_count + 1
+             WHERE id = ?1
+               AND (expires_at IS NULL OR expires_at > ?2)
+               AND (max_clicks IS NULL OR click_count < max_clicks)",
+            params![id, Utc::now().timestamp()],
+        )? > 0;
+        if counted {
+            if let Some(position) = target {
+                tx.execute(
+                    "UPDATE link_targets SET click_count = click_count + 1 WHERE url_id = ?1 AND position = ?2",
+                    params![id, position as i64],
+                )?;
+            }
+            // A failed insert only loses one data point, so never block the redirect on it
+            if let Err(e) = analytics::record_click(&tx, id, click, &self.ip_salt) {
+                eprintln!("Failed to record click for {}: {}", id, e);
+            }
+        }
+        tx.commit()?;
+        Ok(counted)
+    }
+
+    fn record_failed_password(&self, id: &str) -> Result<(), AppError> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "UPDATE urls SET failed_password_attempts = failed_password_attempts + 1 WHERE id = ?1",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    fn list(&self, owner_id: i64, query: &LinkQuery) -> Result<LinkPage, AppError> {
+        let conn = self.pool.get()?;
+        let mut filters = vec!["owner_id = ?"];
+        let mut values = vec![Value::from(owner_id)];
+        if let Some(expression) = query.text.as_deref().and_then(match_expression) {
+            filters.push("rowid IN (SELECT rowid FROM links_fts WHERE links_fts MATCH ?)");
+            values.push(Value::from(expression));
+        }
+        if let Some(tag) = &query.tag {
+            filters.push("instr(' ' || tags || ' ', ' ' || ? || ' ') > 0");
+            values.push(Value::from(tag.to_lowercase()));
+        }
+        if let Some(created_after) = query.created_after {
+            filters.push("created_at > ?");
+            values.push(Value::from(created_after));
+        }
+        let filters = filters.join(" AND ");
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM urls WHERE {}", filters),
+            params_from_iter(&values),
+            |row| row.get(0),
+        )?;
+        values.push(Value::from(query.limit as i64));
+        values.push(Value::from(query.offset as i64));
+        let mut stmt = conn
+            .prepare(&format!("SELECT {} FROM urls WHERE {} ORDER BY rowid LIMIT ? OFFSET ?", LINK_COLUMNS, filters))?;
+        let links = stmt.query_map(params_from_iter(&values), read_link)?.collect::<rusqlite::Result<Vec<_>>>()?;
+        let links = links.into_iter().map(|link| with_targets(&conn, link)).collect::<rusqlite::Result<_>>()?;
+        Ok(LinkPage { links, total: total as usize })
+    }
+
+    fn update(&self, id: &str, update: &LinkUpdate, changed_by: i64) -> Result<Option<StoredLink>, AppError> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let before = match tx
+            .query_row(&format!("SELECT {} FROM urls WHERE id = ?1", LINK_COLUMNS), params![id], read_link)
+            .optional()?
+        {
+            Some(link) => link,
+            None => return Ok(None),
+        };
+        let link = tx
+            .query_row(
+                &format!(
+                    "UPDATE urls SET original_url = COALESCE(?1, original_url),
+                         warn_before_redirect = COALESCE(?2, warn_before_redirect),
+                         redirect_status = COALESCE(?3, redirect_status),
+                         forward_query = COALESCE(?4, forward_query),
+                         title = CASE WHEN ?5 IS NULL THEN title ELSE NULLIF(?5, '') END,
+                         tags = CASE WHEN ?6 IS NULL THEN tags ELSE NULLIF(?6, '') END,
+                         note = CASE WHEN ?7 IS NULL THEN note ELSE NULLIF(?7, '') END
+                     WHERE id = ?8
+                     RETURNING {}",
+                    LINK_COLUMNS
+                ),
+                params![
+                    update.original_url,
+                    update.warn_before_redirect,
+                    update.redirect_status,
+                    update.forward_query,
+                    update.title,
+                    update.tags.as_deref().map(join_tags),
+                    update.note,
+                    id
+                ],
+                read_link,
+            )
+            .optional()?;
+        let link = match link {
+            Some(link) => link,
+            None => return Ok(None),
+        };
+        if let Some(targets) = &update.targets {
+            tx.execute("DELETE FROM link_targets WHERE url_id = ?1", params![id])?;
+            insert_targets(&tx, id, targets)?;
+        }
+        if link.original_url != before.original_url {
+            record_version(&tx, &before, &link.original_url, changed_by)?;
+        }
+        let link = with_targets(&tx, link)?;
+        tx.commit()?;
+        Ok(Some(link))
+    }
+
+    fn versions(&self, id: &str) -> Result<Option<Vec<LinkVersion>>, AppError> {
+        let conn = self.pool.get()?;
+        let link = match conn
+            .query_row(&format!("SELECT {} FROM urls WHERE id = ?1", LINK_COLUMNS), params![id], read_link)
+            .optional()?
+        {
+            Some(link) => link,
+            None => return Ok(None),
+        };
+        let versions = load_versions(&conn, id)?;
+        if versions.is_empty() {
+            return Ok(Some(vec![LinkVersion::first(&link)]));
+        }
+        Ok(Some(versions))
+    }
+
+    fn delete(&self, id: &str) -> Result<bool, AppError> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM clicks WHERE url_id = ?1", params![id])?;
+        tx.execute("DELETE FROM link_targets WHERE url_id = ?1", params![id])?;
+        tx.execute("DELETE FROM link_versions WHERE url_id = ?1", params![id])?;
+        let deleted = tx.execute("DELETE FROM urls WHERE id = ?1", params![id])?;
+        tx.commit()?;
+        Ok(deleted > 0)
+    }
+
+    fn stats(&self, id: &str) -> Result<Option<LinkStats>, AppError> {
+        let conn = self.pool.get()?;
+        let exists: bool =
+            conn.query_row("SELECT COUNT(*) > 0 FROM urls WHERE id = ?1", params![id], |row| row.get(0))?;
+        if !exists {
+            return Ok(None);
+        }
+        Ok(Some(analytics::link_stats(&conn, id)?))
+    }
+
+    fn owner_for_key(&self, key: &str) -> Result<Option<i64>, AppError> {
+        let conn = self.pool.get()?;
+        Ok(auth::lookup_owner(&conn, key)?)
+    }
+
+    fn sweep_expired(&self) -> Result<usize, AppError> {
+        let conn = self.pool.get()?;
+        let now = Utc::now().timestamp();
+        conn.execute(
+            "DELETE FROM clicks WHERE url_id IN (SELECT id FROM urls WHERE expires_at <= ?1)",
+            params![now],
+        )?;
+        conn.execute(
+            "DELETE FROM link_targets WHERE url_id IN (SELECT id FROM urls WHERE expires_at <= ?1)",
+            params![now],
+        )?;
+        conn.execute(
+            "DELETE FROM link_versions WHERE url_id IN (SELECT id FROM urls WHERE expires_at <= ?1)",
+            params![now],
+        )?;
+        Ok(conn.execute("DELETE FROM urls WHERE expires_at <= ?1", params![now])?)
+    }
+}
+
+fn create_link(conn: &Connection, config: &Config, link: &NewLink) -> Result<Created, AppError> {
+    let id = match &link.alias {
+        Some(alias) => match insert_url(conn, alias, link) {
+            Ok(()) => alias.clone(),
+            Err(e) if is_unique_violation(&e) => {
+                return Err(AppError::Conflict(format!("Alias '{}' is already in use", alias)));
+            }
+            Err(e) => return Err(e.into()),
+        },
+        // Plain links with no limits are interchangeable, so hand back the existing one
+        None if link.is_reusable() => match find_reusable_link(conn, link)? {
+            Some(id) => return Ok(Created { id, inserted: false }),
+            None => insert_generated(conn, config, link)?,
+        },
+        None => insert_generated(conn, config, link)?,
+    };
+    Ok(Created { id, inserted: true })
+}
+
+pub fn insert_generated(conn: &Connection, config: &Config, link: &NewLink) -> Result<String, AppError> {
+    IdGenerator::new(config).generate(
+        &link.original_url,
+        || next_in_sequence(conn),
+        |id| match insert_url(conn, id, link) {
+            Ok(()) => Ok(true),
+            Err(e) if is_unique_violation(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        },
+    )
+}
+
+// Numbers handed out stay used even if the link is never stored, unless the surrounding transaction rolls back
+fn next_in_sequence(conn: &Connection) -> Result<u64, AppError> {
+    let value: i64 =
+        conn.query_row("UPDATE id_sequence SET value = value + 1 WHERE id = 1 RETURNING value", [], |row| row.get(0))?;
+    Ok(value as u64)
+}
+
+// Turns the words of a search into an FTS5 query matching links that contain all of them as word prefixes;
+// quoting each word keeps FTS5 operators and punctuation in the search from being interpreted
+fn match_expression(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"*", word))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+// Tags are stored space-separated, NULL when there are none
+pub fn join_tags(tags: &[String]) -> String {
+    tags.join(" ")
+}
+
+pub fn split_tags(tags: Option<String>) -> Vec<String> {
+    tags.map(|tags| tags.split_whitespace().map(str::to_string).collect()).unwrap_or_default()
+}
+
+// Only links of the same owner are reused, so nobody receives a link someone else can retarget or delete
+fn find_reusable_link(conn: &Connection, link: &NewLink) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT id FROM urls
+         WHERE original_url = ?1 AND owner_id IS ?2 AND expires_at IS NULL AND max_clicks IS NULL
+           AND warn_before_redirect = ?3 AND password_hash IS NULL AND redirect_status = ?4 AND forward_query = ?5
+           AND title IS NULL AND tags IS NULL AND note IS NULL
+           AND NOT EXISTS (SELECT 1 FROM link_targets WHERE url_id = urls.id)
+         ORDER BY rowid LIMIT 1",
+        params![
+            link.original_url,
+            link.owner_id,
+            link.warn_before_redirect,
+            link.redirect_status,
+            link.forward_query
+        ],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+pub fn insert_url(conn: &Connection, id: &str, link: &NewLink) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO urls (id, original_url, owner_id, expires_at, max_clicks, warn_before_redirect, password_hash,
+                           redirect_status, forward_query, title, tags, note, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, NULLIF(?11, ''), ?12, ?13)",
+        params![
+            id,
+            link.original_url,
+            link.owner_id,
+            link.expires_at,
+            link.max_clicks,
+            link.warn_before_redirect,
+            link.password_hash,
+            link.redirect_status,
+            link.forward_query,
+            link.title,
+            join_tags(&link.tags),
+            link.note,
+            Utc::now().timestamp()
+        ],
+    )?;
+    insert_targets(conn, id, &link.targets)
+}
+
+// Positions are the targets' indexes, which is how record_hit refers to them
+pub fn insert_targets(conn: &Connection, id: &str, targets: &[Target]) -> rusqlite::Result<()> {
+    for (position, target) in targets.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO link_targets (url_id, position, url, device, language, weight, click_count)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                id,
+                position as i64,
+                target.url,
+                target.when.device.map(Device::name),
+                target.when.language,
+                target.weight,
+                target.click_count
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn load_targets(conn: &Connection, id: &str) -> rusqlite::Result<Vec<Target>> {
+    let mut stmt = conn.prepare(
+        "SELECT url, device, language, weight, click_count FROM link_targets WHERE url_id = ?1 ORDER BY position",
+    )?;
+    let targets = stmt
+        .query_map(params![id], |row| {
+            let device: Option<String> = row.get(1)?;
+            Ok(Target {
+                url: row.get(0)?,
+                when: Condition { device: device.as_deref().and_then(Device::from_name), language: row.get(2)? },
+                weight: row.get(3)?,
+                click_count: row.get(4)?,
+            })
+        })?
+        .collect();
+    targets
+}
+
+// History is only written once a link is first retargeted, starting with the destination it was created with
+fn record_version(conn: &Connection, before: &StoredLink, original_url: &str, changed_by: i64) -> rusqlite::Result<()> {
+    let latest: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM link_versions WHERE url_id = ?1",
+        params![before.id],
+        |row| row.get(0),
+    )?;
+    let mut version = latest;
+    if latest == 0 {
+        let first = LinkVersion::first(before);
+        insert_version(conn, &before.id, &first)?;
+        version = first.version;
+    }
+    let next = LinkVersion {
+        version: version + 1,
+        original_url: original_url.to_string(),
+        changed_by: Some(changed_by),
+        changed_at: Some(Utc::now().timestamp()),
+    };
+    insert_version(conn, &before.id, &next)
+}
+
+fn insert_version(conn: &Connection, id: &str, version: &LinkVersion) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO link_versions (url_id, version, original_url, changed_by, changed_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![id, version.version, version.original_url, version.changed_by, version.changed_at],
+    )?;
+    Ok(())
+}
+
+fn load_versions(conn: &Connection, id: &str) -> rusqlite::Result<Vec<LinkVersion>> {
+    let mut stmt = conn.prepare(
+        "SELECT version, original_url, changed_by, changed_at FROM link_versions WHERE url_id = ?1 ORDER BY version",
+    )?;
+    let versions = stmt
+        .query_map(params![id], |row| {
+            Ok(LinkVersion {
+                version: row.get(0)?,
+                original_url: row.get(1)?,
+                changed_by: row.get(2)?,
+                changed_at: row.get(3)?,
+            })
+        })?
+        .collect();
+    versions
+}
+
+fn with_targets(conn: &Connection, mut link: StoredLink) -> rusqlite::Result<StoredLink> {
+    link.targets = load_targets(conn, &link.id)?;
+    Ok(link)
+}
+
+pub fn is_unique_violation(err: &rusqlite::Error) -> bool {
+    matches!(err, rusqlite::Error::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation)
+}
+
+fn read_link(row: &Row) -> rusqlite::Result<StoredLink> {
+    Ok(StoredLink {
+        id: row.get(0)?,
+        original_url: row.get(1)?,
+        owner_id: row.get(2)?,
+        created_at: row.get(3)?,
+        expires_at: row.get(4)?,
+        max_clicks: row.get(5)?,
+        click_count: row.get(6)?,
+        warn_before_redirect: row.get(7)?,
+        password_hash: row.get(8)?,
+        failed_password_attempts: row.get(9)?,
+        redirect_status: row.get(10)?,
+        forward_query: row.get(11)?,
+        title: row.get(12)?,
+        tags: split_tags(row.get(13)?),
+        note: row.get(14)?,
+        // Filled in by with_targets
+        targets: Vec::new(),
+    })
+}
@@ -0,0 +1,181 @@
+// Alternative destinations of a short link, picked per visit by device, language and weight
+//
+// Targets are checked in order. The first one whose condition matches the visitor decides the group: it
+// and every later target with the same condition split the visits between them by weight. Visitors no
+// target matches go to the link's original_url.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::analytics::Click;
+use crate::policy::Policy;
+use crate::validation;
+
+const MAX_TARGETS: usize = 20;
+const MAX_WEIGHT: u32 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Condition::is_any")]
+    pub when: Condition,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    // Visits sent to this target; only read back from exports, new targets start at 0
+    #[serde(default)]
+    pub click_count: i64,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+// Every field that is set has to match; an empty condition matches everyone
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Condition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Device>,
+    // Compared with the visitor's preferred Accept-Language; "pt" also matches "pt-BR"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    // Any phone or tablet, including iOS and Android
+    Mobile,
+    Desktop,
+    Ios,
+    Android,
+}
+
+impl Device {
+    pub fn from_name(name: &str) -> Option<Device> {
+        match name {
+            "mobile" => Some(Device::Mobile),
+            "desktop" => Some(Device::Desktop),
+            "ios" => Some(Device::Ios),
+            "android" => Some(Device::Android),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::Mobile => "mobile",
+            Device::Desktop => "desktop",
+            Device::Ios => "ios",
+            Device::Android => "android",
+        }
+    }
+
+    fn matches(self, user_agent: &str) -> bool {
+        let ios = ["iPhone", "iPad", "iPod"].iter().any(|s| user_agent.contains(s));
+        let android = user_agent.contains("Android");
+        let mobile = ios || android || user_agent.contains("Mobile");
+        match self {
+            Device::Mobile => mobile,
+            Device::Desktop => !mobile,
+            Device::Ios => ios,
+            Device::Android => android,
+        }
+    }
+}
+
+impl Condition {
+    pub fn is_any(&self) -> bool {
+        self.device.is_none() && self.language.is_none()
+    }
+
+    fn matches(&self, click: &Click) -> bool {
+        let device_matches = self.device.is_none_or(|device| device.matches(click.user_agent.as_deref().unwrap_or("")));
+        let language_matches = self.language.as_ref().is_none_or(|wanted| {
+            preferred_language(click.accept_language.as_deref()).is_some_and(|tag| language_matches(wanted, &tag))
+        });
+        device_matches && language_matches
+    }
+}
+
+// Checks and normalizes targets sent by a client or read from an import
+pub fn validate_targets(targets: &mut [Target], policy: &Policy) -> Result<(), String> {
+    if targets.len() > MAX_TARGETS {
+        return Err(format!("A link may have at most {} targets", MAX_TARGETS));
+    }
+    for (i, target) in targets.iter_mut().enumerate() {
+        let position = i + 1;
+        target.url = validation::normalize_url(&target.url).map_err(|e| format!("Target {}: {}", position, e))?;
+        if let Some(reason) = policy.check(&target.url) {
+            return Err(format!("Target {}: {}", position, reason));
+        }
+        if !(1..=MAX_WEIGHT).contains(&target.weight) {
+            return Err(format!("Target {}: weight must be between 1 and {}", position, MAX_WEIGHT));
+        }
+        if let Some(language) = &mut target.when.language {
+            if language.is_empty() || !language.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err(format!("Target {}: language must be a language tag such as 'de' or 'pt-BR'", position));
+            }
+            *language = language.to_ascii_lowercase();
+        }
+        if target.click_count < 0 {
+            return Err(format!("Target {}: click_count must not be negative", position));
+        }
+    }
+    Ok(())
+}
+
+// The index of the target this visit goes to, or None for the link's original_url
+pub fn choose(targets: &[Target], link_id: &str, click: &Click) -> Option<usize> {
+    let first = targets.iter().position(|t| t.when.matches(click))?;
+    let group: Vec<usize> = (first..targets.len()).filter(|&i| targets[i].when == targets[first].when).collect();
+    let total: u64 = group.iter().map(|&i| u64::from(targets[i].weight)).sum();
+
+    let mut point = bucket(link_id, click) % total;
+    for i in group {
+        let weight = u64::from(targets[i].weight);
+        if point < weight {
+            return Some(i);
+        }
+        point -= weight;
+    }
+    None
+}
+
+// The same visitor lands in the same bucket of a link on every visit, so split tests stay consistent. The seed
+// is the address from `rate_limit::client_ip`, so a visitor can't pick a bucket with their own X-Forwarded-For.
+fn bucket(link_id: &str, click: &Click) -> u64 {
+    let seed = match &click.client_ip {
+        Some(ip) => ip.clone(),
+        None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos().to_string(),
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(link_id.as_bytes());
+    hasher.update(seed.as_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+// The language tag with the highest q-value, lowercased
+fn preferred_language(header: Option<&str>) -> Option<String> {
+    header?
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then(|| (tag.to_ascii_lowercase(), quality))
+        })
+        // max_by keeps the last of equal maxima, so walk the list backwards to prefer the first one listed
+        .rev()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(tag, _)| tag)
+}
+
+fn language_matches(wanted: &str, tag: &str) -> bool {
+    tag == wanted || tag.strip_prefix(wanted).is_some_and(|rest| rest.starts_with('-'))
+}
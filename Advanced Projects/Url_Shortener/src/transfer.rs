@@ -0,0 +1,351 @@
+// Moving links between instances: `export` writes every link as JSON Lines or CSV and `import` loads
+// such a file into another database
+//
+// cargo run -- export --format csv --output links.csv
+// cargo run -- --database staging.db import links.csv --on-conflict rename
+//
+// Click counts travel with the links, individual click records and version histories do not. API keys
+// belong to one instance, so the exported owner is the key's name for reference only; imported links
+// belong to --owner if given.
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::policy::Policy;
+use crate::store::sqlite::{
+    insert_generated, insert_targets, insert_url, is_unique_violation, join_tags, load_targets, split_tags,
+};
+use crate::targeting::{self, Target};
+use crate::{password, validation, NewLink};
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    JsonLines,
+    Csv,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "jsonl" => Some(Format::JsonLines),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    // Files ending in .csv are CSV, everything else is assumed to be JSON Lines
+    pub fn from_path(path: &str) -> Format {
+        if path.to_ascii_lowercase().ends_with(".csv") {
+            Format::Csv
+        } else {
+            Format::JsonLines
+        }
+    }
+}
+
+// What to do with an imported link whose ID is already taken
+#[derive(Clone, Copy)]
+pub enum ConflictPolicy {
+    Skip,
+    Overwrite,
+    Rename,
+}
+
+impl ConflictPolicy {
+    pub fn from_name(name: &str) -> Option<ConflictPolicy> {
+        match name {
+            "skip" => Some(ConflictPolicy::Skip),
+            "overwrite" => Some(ConflictPolicy::Overwrite),
+            "rename" => Some(ConflictPolicy::Rename),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LinkRecord {
+    id: String,
+    original_url: String,
+    expires_at: Option<i64>,
+    max_clicks: Option<i64>,
+    #[serde(default)]
+    click_count: i64,
+    created_at: Option<i64>,
+    #[serde(default)]
+    warn_before_redirect: bool,
+    // Argon2 hash of the link's password, moved as is so protected links stay protected
+    password_hash: Option<String>,
+    #[serde(default = "default_redirect_status")]
+    redirect_status: u16,
+    #[serde(default)]
+    forward_query: bool,
+    // With their click counts, as a JSON array
+    #[serde(default, with = "targets_json")]
+    targets: Vec<Target>,
+    title: Option<String>,
+    // Comma-separated, for the same reason as targets
+    #[serde(default, serialize_with = "serialize_tags", deserialize_with = "deserialize_tags")]
+    tags: Vec<String>,
+    note: Option<String>,
+    owner: Option<String>,
+}
+
+fn serialize_tags<S: Serializer>(tags: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&tags.join(","))
+}
+
+// Read as text, since CSV would otherwise guess that a tag like "inf" or "1e5" is a number
+fn deserialize_tags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    Ok(validation::split_tag_list(&String::deserialize(deserializer)?))
+}
+
+// CSV cells can't nest, so in both formats targets are written as one JSON string, empty when there are none
+mod targets_json {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use crate::targeting::Target;
+
+    pub fn serialize<S: Serializer>(targets: &[Target], serializer: S) -> Result<S::Ok, S::Error> {
+        if targets.is_empty() {
+            return serializer.serialize_str("");
+        }
+        let json = serde_json::to_string(targets).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&json)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Target>, D::Error> {
+        let json = String::deserialize(deserializer)?;
+        if json.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        serde_json::from_str(&json).map_err(|e| de::Error::custom(format!("invalid targets: {}", e)))
+    }
+}
+
+// Exports from before redirect_status existed always redirected with 302
+fn default_redirect_status() -> u16 {
+    validation::DEFAULT_REDIRECT_STATUS
+}
+
+#[derive(Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+    pub overwritten: usize,
+    // Old and new ID of every link that was imported under a new ID
+    pub renamed: Vec<(String, String)>,
+}
+
+// Returns the number of links written
+pub fn export_links(conn: &Connection, format: Format, out: impl Write) -> Result<usize, String> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT urls.id, urls.original_url, urls.expires_at, urls.max_clicks, urls.click_count, urls.created_at,
+                    urls.warn_before_redirect, urls.password_hash, urls.redirect_status, urls.forward_query,
+                    urls.title, urls.tags, urls.note, api_keys.name
+             FROM urls LEFT JOIN api_keys ON api_keys.id = urls.owner_id
+             ORDER BY urls.rowid",
+        )
+        .map_err(|e| format!("Failed to read links: {}", e))?;
+    let mut records = stmt
+        .query_map([], |row| {
+            Ok(LinkRecord {
+                id: row.get(0)?,
+                original_url: row.get(1)?,
+                expires_at: row.get(2)?,
+                max_clicks: row.get(3)?,
+                click_count: row.get(4)?,
+                created_at: row.get(5)?,
+                warn_before_redirect: row.get(6)?,
+                password_hash: row.get(7)?,
+                redirect_status: row.get(8)?,
+                forward_query: row.get(9)?,
+                targets: Vec::new(),
+                title: row.get(10)?,
+                tags: split_tags(row.get(11)?),
+                note: row.get(12)?,
+                owner: row.get(13)?,
+            })
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(|e| format!("Failed to read links: {}", e))?;
+    for record in &mut records {
+        record.targets = load_targets(conn, &record.id).map_err(|e| format!("Failed to read links: {}", e))?;
+    }
+
+    match format {
+        Format::JsonLines => {
+            let mut out = out;
+            for record in &records {
+                let line = serde_json::to_string(record).map_err(|e| format!("Failed to encode link: {}", e))?;
+                writeln!(out, "{}", line).map_err(|e| format!("Failed to write export: {}", e))?;
+            }
+            out.flush().map_err(|e| format!("Failed to write export: {}", e))?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for record in &records {
+                writer.serialize(record).map_err(|e| format!("Failed to write export: {}", e))?;
+            }
+            writer.flush().map_err(|e| format!("Failed to write export: {}", e))?;
+        }
+    }
+    Ok(records.len())
+}
+
+// Parses and validates the whole file up front, so a bad record is reported before anything is imported
+pub fn read_records(input: impl Read, format: Format, policy: &Policy) -> Result<Vec<LinkRecord>, Vec<String>> {
+    // Each parsed record paired with the line it starts on
+    let mut parsed: Vec<(u64, Result<LinkRecord, String>)> = Vec::new();
+    match format {
+        Format::JsonLines => {
+            for (i, line) in BufReader::new(input).lines().enumerate() {
+                let line = line.map_err(|e| vec![format!("Failed to read input: {}", e)])?;
+                if !line.trim().is_empty() {
+                    parsed.push((i as u64 + 1, serde_json::from_str(&line).map_err(|e| e.to_string())));
+                }
+            }
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let headers = reader.headers().map_err(|e| vec![format!("Invalid CSV header: {}", e)])?.clone();
+            for row in reader.records() {
+                let row = row.map_err(|e| vec![format!("Invalid CSV row: {}", e)])?;
+                let line = row.position().map_or(0, |p| p.line());
+                parsed.push((line, row.deserialize(Some(&headers)).map_err(|e| e.to_string())));
+            }
+        }
+    }
+
+    let mut records = Vec::new();
+    let mut errors = Vec::new();
+    for (line, record) in parsed {
+        match record.and_then(|record| validate_record(record, policy)) {
+            Ok(record) => records.push(record),
+            Err(e) => errors.push(format!("line {}: {}", line, e)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(records)
+    } else {
+        Err(errors)
+    }
+}
+
+fn validate_record(mut record: LinkRecord, policy: &Policy) -> Result<LinkRecord, String> {
+    validation::validate_short_id(&record.id)?;
+    record.original_url = validation::normalize_url(&record.original_url)?;
+    if let Some(reason) = policy.check(&record.original_url) {
+        return Err(reason);
+    }
+    if matches!(record.max_clicks, Some(n) if n < 1) {
+        return Err("max_clicks must be at least 1".to_string());
+    }
+    if let Some(hash) = &record.password_hash {
+        password::validate_hash(hash)?;
+    }
+    validation::validate_redirect_status(record.redirect_status)?;
+    targeting::validate_targets(&mut record.targets, policy)?;
+    record.title = match &record.title {
+        Some(title) => validation::normalize_title(title)?,
+        None => None,
+    };
+    record.note = match &record.note {
+        Some(note) => validation::normalize_note(note)?,
+        None => None,
+    };
+    record.tags = validation::normalize_tags(&record.tags)?;
+    if record.click_count < 0 {
+        return Err("click_count must not be negative".to_string());
+    }
+    Ok(record)
+}
+
+// Imports every record in one transaction, so a failure leaves the database unchanged
+pub fn import_links(
+    conn: &mut Connection,
+    config: &Config,
+    records: Vec<LinkRecord>,
+    policy: ConflictPolicy,
+    owner_id: Option<i64>,
+) -> Result<ImportSummary, AppError> {
+    let tx = conn.transaction()?;
+    let mut summary = ImportSummary::default();
+
+    for record in records {
+        let link = NewLink {
+            original_url: record.original_url,
+            alias: None,
+            owner_id,
+            expires_at: record.expires_at,
+            max_clicks: record.max_clicks,
+            warn_before_redirect: record.warn_before_redirect,
+            password_hash: record.password_hash,
+            redirect_status: record.redirect_status,
+            forward_query: record.forward_query,
+            targets: record.targets,
+            title: record.title,
+            tags: record.tags,
+            note: record.note,
+        };
+        let id = match insert_url(&tx, &record.id, &link) {
+            Ok(()) => {
+                summary.imported += 1;
+                record.id
+            }
+            Err(e) if is_unique_violation(&e) => match policy {
+                ConflictPolicy::Skip => {
+                    summary.skipped += 1;
+                    continue;
+                }
+                ConflictPolicy::Overwrite => {
+                    // The old link's clicks would otherwise be attributed to the new destination
+                    tx.execute("DELETE FROM clicks WHERE url_id = ?1", params![record.id])?;
+                    tx.execute("DELETE FROM link_targets WHERE url_id = ?1", params![record.id])?;
+                    tx.execute("DELETE FROM link_versions WHERE url_id = ?1", params![record.id])?;
+                    insert_targets(&tx, &record.id, &link.targets)?;
+                    tx.execute(
+                        "UPDATE urls SET original_url = ?2, owner_id = ?3, expires_at = ?4, max_clicks = ?5,
+                             warn_before_redirect = ?6, password_hash = ?7, redirect_status = ?8,
+                             forward_query = ?9, title = ?10, tags = NULLIF(?11, ''), note = ?12,
+                             failed_password_attempts = 0
+                         WHERE id = ?1",
+                        params![
+                            record.id,
+                            link.original_url,
+                            link.owner_id,
+                            link.expires_at,
+                            link.max_clicks,
+                            link.warn_before_redirect,
+                            link.password_hash,
+                            link.redirect_status,
+                            link.forward_query,
+                            link.title,
+                            join_tags(&link.tags),
+                            link.note
+                        ],
+                    )?;
+                    summary.overwritten += 1;
+                    record.id
+                }
+                ConflictPolicy::Rename => {
+                    let new_id = insert_generated(&tx, config, &link)?;
+                    summary.renamed.push((record.id, new_id.clone()));
+                    new_id
+                }
+            },
+            Err(e) => return Err(e.into()),
+        };
+        // Keep the original creation time; records from before it was tracked get the import time
+        tx.execute(
+            "UPDATE urls SET click_count = ?1, created_at = COALESCE(?2, created_at) WHERE id = ?3",
+            params![record.click_count, record.created_at, id],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(summary)
+}
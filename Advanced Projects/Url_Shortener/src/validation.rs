@@ -0,0 +1,271 @@
+// Checks applied to user-supplied aliases and destination URLs before they reach the database
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::Deserializer;
+use std::fmt;
+use url::Url;
+
+// Paths that are routes of their own and must never be handed out as aliases
+const RESERVED_ALIASES: &[&str] = &["shorten", "links", "cache", "metrics", "dashboard"];
+const MIN_ALIAS_LEN: usize = 3;
+const MAX_ALIAS_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 4;
+const MAX_PASSWORD_LEN: usize = 128;
+// Permanent (301, 308) and temporary (302, 307) redirects; 307 and 308 keep the request method
+const REDIRECT_STATUSES: &[u16] = &[301, 302, 307, 308];
+pub const DEFAULT_REDIRECT_STATUS: u16 = 302;
+const MAX_TITLE_LEN: usize = 200;
+const MAX_NOTE_LEN: usize = 2000;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LEN: usize = 32;
+
+// Aliases become part of the URL path, so keep them to a URL-safe character set
+pub fn validate_alias(alias: &str) -> Result<(), String> {
+    let len = alias.chars().count();
+    if !(MIN_ALIAS_LEN..=MAX_ALIAS_LEN).contains(&len) {
+        return Err(format!(
+            "Alias must be between {} and {} characters long",
+            MIN_ALIAS_LEN, MAX_ALIAS_LEN
+        ));
+    }
+    if !alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Alias may only contain letters, digits, '-' and '_'".to_string());
+    }
+    if is_reserved(alias) {
+        return Err(format!("Alias '{}' is reserved", alias));
+    }
+    Ok(())
+}
+
+pub fn is_reserved(id: &str) -> bool {
+    RESERVED_ALIASES.iter().any(|r| r.eq_ignore_ascii_case(id))
+}
+
+pub fn validate_redirect_status(status: u16) -> Result<(), String> {
+    if !REDIRECT_STATUSES.contains(&status) {
+        return Err(format!("redirect_status must be 301, 302, 307 or 308, got {}", status));
+    }
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), String> {
+    let len = password.chars().count();
+    if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&len) {
+        return Err(format!(
+            "Password must be between {} and {} characters long",
+            MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+        ));
+    }
+    Ok(())
+}
+
+// IDs imported from another instance may use that instance's alphabet and length, so only require
+// that they fit in a single path segment and don't shadow a route. '+' is out because "/{id}+" is the
+// preview route, which would catch an ID like "abc+" first.
+pub fn validate_short_id(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("Short ID must not be empty".to_string());
+    }
+    if id.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '/' | '?' | '#' | '%' | '+')) {
+        return Err(format!("Short ID '{}' is not a valid path segment", id));
+    }
+    if is_reserved(id) {
+        return Err(format!("Short ID '{}' is reserved", id));
+    }
+    Ok(())
+}
+
+// Surrounding whitespace is dropped and an empty title means no title
+pub fn normalize_title(title: &str) -> Result<Option<String>, String> {
+    let title = title.trim();
+    if title.chars().count() > MAX_TITLE_LEN {
+        return Err(format!("Title must be at most {} characters long", MAX_TITLE_LEN));
+    }
+    Ok((!title.is_empty()).then(|| title.to_string()))
+}
+
+pub fn normalize_note(note: &str) -> Result<Option<String>, String> {
+    let note = note.trim();
+    if note.chars().count() > MAX_NOTE_LEN {
+        return Err(format!("Note must be at most {} characters long", MAX_NOTE_LEN));
+    }
+    Ok((!note.is_empty()).then(|| note.to_string()))
+}
+
+// Tags are single words, compared case-insensitively, so they are lowercased and deduplicated in order
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.chars().count() > MAX_TAG_LEN {
+            return Err(format!("Tags must be between 1 and {} characters long", MAX_TAG_LEN));
+        }
+        if !tag.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("Tag '{}' may only contain letters, digits, '-' and '_'", tag));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_TAGS {
+        return Err(format!("A link may have at most {} tags", MAX_TAGS));
+    }
+    Ok(normalized)
+}
+
+// The tags of one comma-separated CSV cell
+pub fn split_tag_list(text: &str) -> Vec<String> {
+    text.split(',').map(str::trim).filter(|t| !t.is_empty()).map(str::to_string).collect()
+}
+
+// Tags arrive as a JSON array, or as one comma-separated cell in CSV uploads
+pub fn deserialize_tags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    struct TagsVisitor;
+
+    impl<'de> Visitor<'de> for TagsVisitor {
+        type Value = Vec<String>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a list of tags or a comma-separated string")
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<Vec<String>, E> {
+            Ok(split_tag_list(text))
+        }
+
+        // CSV guesses the type of every cell, so a lone tag like "2030", "1e5" or "true" shows up as a number
+        // or bool. Printing it back can't restore "007" or "nan", so CSV readers take the tags from the cell
+        // text afterwards; accepting every type here only keeps such a row from failing first.
+        fn visit_u64<E: de::Error>(self, n: u64) -> Result<Vec<String>, E> {
+            Ok(vec![n.to_string()])
+        }
+
+        fn visit_i64<E: de::Error>(self, n: i64) -> Result<Vec<String>, E> {
+            Ok(vec![n.to_string()])
+        }
+
+        fn visit_u128<E: de::Error>(self, n: u128) -> Result<Vec<String>, E> {
+            Ok(vec![n.to_string()])
+        }
+
+        fn visit_i128<E: de::Error>(self, n: i128) -> Result<Vec<String>, E> {
+            Ok(vec![n.to_string()])
+        }
+
+        fn visit_f64<E: de::Error>(self, n: f64) -> Result<Vec<String>, E> {
+            Ok(vec![n.to_string()])
+        }
+
+        fn visit_bool<E: de::Error>(self, b: bool) -> Result<Vec<String>, E> {
+            Ok(vec![b.to_string()])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<String>, A::Error> {
+            let mut tags = Vec::new();
+            while let Some(tag) = seq.next_element()? {
+                tags.push(tag);
+            }
+            Ok(tags)
+        }
+    }
+
+    deserializer.deserialize_any(TagsVisitor)
+}
+
+// An empty CSV cell or a JSON null means false, like leaving the field out
+pub fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    struct FlagVisitor;
+
+    impl<'de> Visitor<'de> for FlagVisitor {
+        type Value = bool;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("true, false or nothing")
+        }
+
+        fn visit_bool<E: de::Error>(self, b: bool) -> Result<bool, E> {
+            Ok(b)
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<bool, E> {
+            match text.trim() {
+                "" | "false" => Ok(false),
+                "true" => Ok(true),
+                other => Err(E::invalid_value(de::Unexpected::Str(other), &self)),
+            }
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<bool, E> {
+            Ok(false)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<bool, E> {
+            Ok(false)
+        }
+    }
+
+    deserializer.deserialize_any(FlagVisitor)
+}
+
+// Only absolute http(s) URLs are accepted; the result is the canonical form used for storage and deduplication
+pub fn normalize_url(raw: &str) -> Result<String, String> {
+    let mut url = Url::parse(raw.trim()).map_err(|e| format!("Invalid URL '{}': {}", raw, e))?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!("Unsupported URL scheme '{}', only http and https are allowed", url.scheme()));
+    }
+
+    // The parser already lowercases the host and drops default ports, leaving the trailing dot of a FQDN
+    let host = match url.host_str() {
+        Some(host) if !host.is_empty() => host.to_string(),
+        _ => return Err("URL must include a host".to_string()),
+    };
+    if let Some(trimmed) = host.strip_suffix('.') {
+        if trimmed.is_empty() {
+            return Err("URL must include a host".to_string());
+        }
+        url.set_host(Some(trimmed)).map_err(|e| format!("Invalid URL host: {}", e))?;
+    }
+
+    Ok(url.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_url_rejects_other_schemes() {
+        assert!(normalize_url("javascript:alert(1)").is_err());
+        assert!(normalize_url("ftp://example.com/file").is_err());
+    }
+
+    #[test]
+    fn test_normalize_url_rejects_relative_urls() {
+        assert!(normalize_url("/just/a/path").is_err());
+        assert!(normalize_url("example.com/page").is_err());
+    }
+
+    #[test]
+    fn test_normalize_url_lowercases_host() {
+        assert_eq!(normalize_url("https://WWW.Example.COM/Path").unwrap(), "https://www.example.com/Path");
+    }
+
+    #[test]
+    fn test_normalize_url_strips_default_ports() {
+        assert_eq!(normalize_url("https://example.com:443/a").unwrap(), "https://example.com/a");
+        assert_eq!(normalize_url("http://example.com:80/a").unwrap(), "http://example.com/a");
+        assert_eq!(normalize_url("http://example.com:8080/a").unwrap(), "http://example.com:8080/a");
+    }
+
+    #[test]
+    fn test_normalize_url_removes_trailing_dot() {
+        assert_eq!(normalize_url("https://example.com./a").unwrap(), "https://example.com/a");
+    }
+
+    #[test]
+    fn test_validate_short_id_rejects_preview_suffix() {
+        assert!(validate_short_id("abc+").is_err());
+        assert!(validate_short_id("abc").is_ok());
+    }
+}